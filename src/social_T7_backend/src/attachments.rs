@@ -0,0 +1,202 @@
+// Attachments for direct messages.
+//
+// Files are uploaded first with `upload_attachment`, then referenced by id
+// when sending a message. The bytes live in `BLOBS`; messages only carry the
+// metadata, and `get_attachment` checks the caller can see the message.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, time};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::events::{self, EventKind};
+use crate::{auth, messaging, validation};
+
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 4;
+/// Uploads not yet sent in a message, per user
+pub const MAX_PENDING_UPLOADS: usize = 8;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachmentKind {
+    Image,
+    File,
+    VoiceNote,
+}
+
+impl AttachmentKind {
+    /// Kept under the 2 MiB ingress limit so an upload fits in one call
+    fn max_bytes(self) -> usize {
+        match self {
+            AttachmentKind::Image => 1_500_000,
+            AttachmentKind::File => 1_800_000,
+            AttachmentKind::VoiceNote => 1_500_000,
+        }
+    }
+
+    fn allowed_mime_types(self) -> &'static [&'static str] {
+        match self {
+            AttachmentKind::Image => validation::IMAGE_MIME_TYPES,
+            AttachmentKind::File => &[
+                "application/pdf",
+                "application/zip",
+                "text/plain",
+                "text/csv",
+                "application/msword",
+                "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            ],
+            AttachmentKind::VoiceNote => &["audio/ogg", "audio/mpeg", "audio/mp4", "audio/webm", "audio/wav"],
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    pub attachment_id: u64,
+    pub kind: AttachmentKind,
+    pub mime_type: String,
+    pub file_name: String,
+    pub size: u64,
+    pub uploaded_by: Principal,
+    pub uploaded_at: u64,
+    /// The message it was sent with; `None` while the upload is pending
+    pub message_id: Option<u64>,
+}
+
+thread_local! {
+    static ATTACHMENTS: RefCell<BTreeMap<u64, Attachment>> = const { RefCell::new(BTreeMap::new()) };
+    static BLOBS: RefCell<BTreeMap<u64, Vec<u8>>> = const { RefCell::new(BTreeMap::new()) };
+    static ATTACHMENT_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+}
+
+fn next_attachment_id() -> u64 {
+    ATTACHMENT_COUNTER.with(|c| {
+        let mut m = c.borrow_mut();
+        *m += 1;
+        *m
+    })
+}
+
+fn pending_uploads(user: Principal) -> usize {
+    ATTACHMENTS.with(|a| {
+        a.borrow()
+            .values()
+            .filter(|att| att.uploaded_by == user && att.message_id.is_none())
+            .count()
+    })
+}
+
+/// Check `ids` are distinct pending uploads of `user`, before anything is sent
+pub fn check_pending(user: Principal, ids: &[u64]) -> Result<(), String> {
+    if ids.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return Err(format!("A message can have at most {} attachments", MAX_ATTACHMENTS_PER_MESSAGE));
+    }
+    for (i, id) in ids.iter().enumerate() {
+        if ids[..i].contains(id) {
+            return Err("Duplicate attachment".to_string());
+        }
+        let ok = ATTACHMENTS.with(|a| {
+            a.borrow()
+                .get(id)
+                .map(|att| att.uploaded_by == user && att.message_id.is_none())
+                .unwrap_or(false)
+        });
+        if !ok {
+            return Err(format!("Attachment {} not found", id));
+        }
+    }
+    Ok(())
+}
+
+/// Tie pending uploads to a message; call after `check_pending`
+pub fn claim(ids: &[u64], message_id: u64) -> Vec<Attachment> {
+    ATTACHMENTS.with(|a| {
+        let mut a = a.borrow_mut();
+        ids.iter()
+            .filter_map(|id| {
+                let att = a.get_mut(id)?;
+                att.message_id = Some(message_id);
+                Some(att.clone())
+            })
+            .collect()
+    })
+}
+
+/// Drop attachments and their bytes, e.g. when their message is unsent or expires
+pub fn delete(attachments: &[Attachment]) {
+    for att in attachments {
+        ATTACHMENTS.with(|a| a.borrow_mut().remove(&att.attachment_id));
+        BLOBS.with(|b| b.borrow_mut().remove(&att.attachment_id));
+    }
+}
+
+/// Upload a file to send with `send_attachment_message`
+#[ic_cdk::update]
+pub fn upload_attachment(
+    kind: AttachmentKind,
+    mime_type: String,
+    file_name: String,
+    data: Vec<u8>,
+) -> Result<Attachment, String> {
+    let me = auth::registered_caller()?;
+    let mime_type = mime_type.trim().to_ascii_lowercase();
+    if !kind.allowed_mime_types().contains(&mime_type.as_str()) {
+        return Err(format!("{} is not an allowed type for this attachment", mime_type));
+    }
+    let file_name = validation::clean_text(&file_name, &validation::FILE_NAME)?;
+    if data.is_empty() {
+        return Err("Attachment cannot be empty".to_string());
+    }
+    if data.len() > kind.max_bytes() {
+        return Err(format!("Attachment is too large: {} bytes (max {})", data.len(), kind.max_bytes()));
+    }
+    if pending_uploads(me) >= MAX_PENDING_UPLOADS {
+        return Err("Too many unsent attachments: send or delete some first".to_string());
+    }
+
+    let attachment = Attachment {
+        attachment_id: next_attachment_id(),
+        kind,
+        mime_type,
+        file_name,
+        size: data.len() as u64,
+        uploaded_by: me,
+        uploaded_at: time(),
+        message_id: None,
+    };
+    ATTACHMENTS.with(|a| a.borrow_mut().insert(attachment.attachment_id, attachment.clone()));
+    BLOBS.with(|b| b.borrow_mut().insert(attachment.attachment_id, data));
+    events::record_for(me, EventKind::AttachmentUploaded { attachment_id: attachment.attachment_id, by: me });
+    Ok(attachment)
+}
+
+/// Discard an upload that has not been sent yet
+#[ic_cdk::update]
+pub fn delete_attachment(attachment_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    check_pending(me, &[attachment_id])?;
+    ATTACHMENTS.with(|a| a.borrow_mut().remove(&attachment_id));
+    BLOBS.with(|b| b.borrow_mut().remove(&attachment_id));
+    events::record_for(me, EventKind::AttachmentDeleted { attachment_id, by: me });
+    Ok("Attachment deleted".to_string())
+}
+
+/// The bytes of an attachment: only the uploader and the participants of
+/// the conversation it was sent in can fetch it
+#[ic_cdk::query]
+pub fn get_attachment(attachment_id: u64) -> Result<Vec<u8>, String> {
+    let me = caller();
+    let attachment = ATTACHMENTS
+        .with(|a| a.borrow().get(&attachment_id).cloned())
+        .ok_or("Attachment not found")?;
+    let allowed = match attachment.message_id {
+        Some(message_id) => messaging::can_view_message(me, message_id),
+        None => attachment.uploaded_by == me,
+    };
+    if !allowed {
+        return Err("Attachment not found".to_string());
+    }
+    BLOBS
+        .with(|b| b.borrow().get(&attachment_id).cloned())
+        .ok_or_else(|| "Attachment not found".to_string())
+}
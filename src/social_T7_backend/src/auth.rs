@@ -0,0 +1,256 @@
+// Caller authentication and registration gating.
+//
+// Every update endpoint resolves its caller through `authenticated_caller` or
+// `registered_caller` instead of calling `caller()` directly, so the anonymous
+// principal can never act as a shared identity.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, is_controller, time};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::events::{self, AdminSetting, EventKind};
+use crate::USERS;
+
+/// Invites a regular user may create unless an admin overrides it
+pub const DEFAULT_INVITE_QUOTA: u32 = 5;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistrationMode {
+    Open,
+    InviteOnly,
+    ApprovalRequired,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Invite {
+    pub code: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub redeemed_by: Option<Principal>,
+    pub redeemed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingRegistration {
+    pub user_principal: Principal,
+    pub requested_at: u64,
+}
+
+thread_local! {
+    static REGISTRATION_MODE: RefCell<RegistrationMode> = const { RefCell::new(RegistrationMode::Open) };
+    static INVITES: RefCell<BTreeMap<String, Invite>> = const { RefCell::new(BTreeMap::new()) };
+    static INVITE_QUOTAS: RefCell<BTreeMap<Principal, u32>> = const { RefCell::new(BTreeMap::new()) };
+    // Principals cleared to register, either by redeeming an invite or by admin approval
+    static APPROVED: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    static PENDING: RefCell<BTreeMap<Principal, PendingRegistration>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Guards
+
+/// The caller, unless it is the anonymous principal
+pub fn authenticated_caller() -> Result<Principal, String> {
+    let principal = caller();
+    if principal == Principal::anonymous() {
+        return Err("Anonymous callers must sign in with Internet Identity".to_string());
+    }
+    Ok(principal)
+}
+
+/// The caller, who must be signed in and have a profile
+pub fn registered_caller() -> Result<Principal, String> {
+    let principal = authenticated_caller()?;
+    if !USERS.with(|users| users.borrow().contains_key(&principal)) {
+        return Err("User not registered".to_string());
+    }
+    Ok(principal)
+}
+
+/// Admins are the canister controllers
+pub fn admin_caller() -> Result<Principal, String> {
+    let principal = authenticated_caller()?;
+    if !is_controller(&principal) {
+        return Err("Unauthorized: admin only".to_string());
+    }
+    Ok(principal)
+}
+
+/// Called by `register_user` before a profile is created.
+/// In approval mode an unapproved caller is queued for review.
+pub fn check_registration_allowed(principal: Principal) -> Result<(), String> {
+    if is_controller(&principal) || APPROVED.with(|a| a.borrow().contains(&principal)) {
+        return Ok(());
+    }
+    match REGISTRATION_MODE.with(|m| *m.borrow()) {
+        RegistrationMode::Open => Ok(()),
+        RegistrationMode::InviteOnly => {
+            Err("Registration is invite-only: redeem an invite code first".to_string())
+        }
+        RegistrationMode::ApprovalRequired => {
+            let queued = PENDING.with(|p| {
+                let mut p = p.borrow_mut();
+                if p.contains_key(&principal) {
+                    return false;
+                }
+                p.insert(principal, PendingRegistration { user_principal: principal, requested_at: time() });
+                true
+            });
+            if queued {
+                events::record_for(principal, EventKind::RegistrationRequested { user: principal });
+            }
+            Err("Registration is pending admin approval".to_string())
+        }
+    }
+}
+
+/// Clears approval bookkeeping once the profile exists
+pub fn registration_completed(principal: Principal) {
+    APPROVED.with(|a| a.borrow_mut().remove(&principal));
+    PENDING.with(|p| p.borrow_mut().remove(&principal));
+}
+
+fn invite_quota(principal: Principal) -> u32 {
+    INVITE_QUOTAS.with(|q| q.borrow().get(&principal).copied().unwrap_or(DEFAULT_INVITE_QUOTA))
+}
+
+fn invites_created_by(principal: Principal) -> u32 {
+    INVITES.with(|i| i.borrow().values().filter(|inv| inv.created_by == principal).count() as u32)
+}
+
+// Admin
+
+#[ic_cdk::update]
+pub fn set_registration_mode(mode: RegistrationMode) -> Result<RegistrationMode, String> {
+    let admin = admin_caller()?;
+    REGISTRATION_MODE.with(|m| *m.borrow_mut() = mode);
+    let kind = EventKind::AdminSettingChanged { by: admin, setting: AdminSetting::RegistrationMode };
+    events::record_private(kind, Vec::new());
+    Ok(mode)
+}
+
+#[ic_cdk::query]
+pub fn get_registration_mode() -> RegistrationMode {
+    REGISTRATION_MODE.with(|m| *m.borrow())
+}
+
+#[ic_cdk::query]
+pub fn get_pending_registrations() -> Result<Vec<PendingRegistration>, String> {
+    admin_caller()?;
+    let mut pending: Vec<PendingRegistration> = PENDING.with(|p| p.borrow().values().cloned().collect());
+    pending.sort_by_key(|p| p.requested_at);
+    Ok(pending)
+}
+
+#[ic_cdk::update]
+pub fn approve_registration(user_principal: Principal) -> Result<String, String> {
+    admin_caller()?;
+    if user_principal == Principal::anonymous() {
+        return Err("Cannot approve the anonymous principal".to_string());
+    }
+    PENDING.with(|p| p.borrow_mut().remove(&user_principal));
+    APPROVED.with(|a| a.borrow_mut().insert(user_principal));
+    events::record_for(user_principal, EventKind::RegistrationApproved { user: user_principal });
+    Ok("Registration approved".to_string())
+}
+
+#[ic_cdk::update]
+pub fn reject_registration(user_principal: Principal) -> Result<String, String> {
+    admin_caller()?;
+    match PENDING.with(|p| p.borrow_mut().remove(&user_principal)) {
+        Some(_) => {
+            events::record_for(user_principal, EventKind::RegistrationRejected { user: user_principal });
+            Ok("Registration rejected".to_string())
+        }
+        None => Err("No pending registration for this user".to_string()),
+    }
+}
+
+#[ic_cdk::update]
+pub fn set_invite_quota(user_principal: Principal, quota: u32) -> Result<String, String> {
+    let admin = admin_caller()?;
+    INVITE_QUOTAS.with(|q| q.borrow_mut().insert(user_principal, quota));
+    let kind = EventKind::AdminSettingChanged { by: admin, setting: AdminSetting::InviteQuota { user: user_principal } };
+    events::record_for(user_principal, kind);
+    Ok("Invite quota updated".to_string())
+}
+
+// Invites
+
+/// Create an invite code. Registered users are limited by their quota; admins are not.
+#[ic_cdk::update]
+pub async fn create_invite() -> Result<Invite, String> {
+    let principal = authenticated_caller()?;
+    let admin = is_controller(&principal);
+    if !admin {
+        registered_caller()?;
+        let quota = invite_quota(principal);
+        if invites_created_by(principal) >= quota {
+            return Err(format!("Invite quota reached ({} invites)", quota));
+        }
+    }
+
+    let (bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, e)| format!("Failed to generate invite code: {}", e))?;
+    let code: String = bytes.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+
+    let invite = Invite {
+        code: code.clone(),
+        created_by: principal,
+        created_at: time(),
+        redeemed_by: None,
+        redeemed_at: None,
+    };
+
+    // re-check after the await: another call may have used up the quota meanwhile
+    if !admin && invites_created_by(principal) >= invite_quota(principal) {
+        return Err("Invite quota reached".to_string());
+    }
+    INVITES.with(|i| i.borrow_mut().insert(code, invite.clone()));
+    // the code itself stays out of the log
+    events::record_for(principal, EventKind::InviteCreated { by: principal });
+    Ok(invite)
+}
+
+/// Redeem an invite code, clearing the caller to call `register_user`
+#[ic_cdk::update]
+pub fn redeem_invite(code: String) -> Result<String, String> {
+    let principal = authenticated_caller()?;
+    if USERS.with(|users| users.borrow().contains_key(&principal)) {
+        return Err("User already registered".to_string());
+    }
+    if APPROVED.with(|a| a.borrow().contains(&principal)) {
+        return Err("You are already cleared to register".to_string());
+    }
+
+    INVITES.with(|i| {
+        let mut invites = i.borrow_mut();
+        match invites.get_mut(code.trim()) {
+            Some(invite) if invite.redeemed_by.is_some() => Err("Invite code already used".to_string()),
+            Some(invite) => {
+                invite.redeemed_by = Some(principal);
+                invite.redeemed_at = Some(time());
+                APPROVED.with(|a| a.borrow_mut().insert(principal));
+                let kind = EventKind::InviteRedeemed { by: principal, invited_by: invite.created_by };
+                events::record_private(kind, vec![principal, invite.created_by]);
+                Ok("Invite redeemed".to_string())
+            }
+            None => Err("Invalid invite code".to_string()),
+        }
+    })
+}
+
+/// Invites created by the caller, with remaining quota
+#[ic_cdk::query]
+pub fn get_my_invites() -> (Vec<Invite>, u32) {
+    let principal = caller();
+    let mine: Vec<Invite> = INVITES.with(|i| {
+        i.borrow()
+            .values()
+            .filter(|inv| inv.created_by == principal)
+            .cloned()
+            .collect()
+    });
+    let remaining = invite_quota(principal).saturating_sub(mine.len() as u32);
+    (mine, remaining)
+}
@@ -0,0 +1,285 @@
+// Daily and weekly digest notifications.
+//
+// Users opt in with `set_digest_frequency`. Activity is tallied as the
+// notifications behind it are raised, so building a digest never scans posts.
+// A timer sends each subscriber's digest once their period is over and starts
+// the next one; `DUE` keeps subscribers ordered by when that happens.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, time};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use crate::notifications::{store_notification, NotificationTarget, NotificationType};
+use crate::events::{self, EventKind, Setting};
+use crate::{auth, messaging, POSTS};
+
+pub const DIGEST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Digests sent per run of the job; the rest wait for the next run
+pub const MAX_DIGEST_BATCH: usize = 500;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DigestFrequency {
+    #[default]
+    Off,
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    fn period(self) -> Option<u64> {
+        match self {
+            DigestFrequency::Off => None,
+            DigestFrequency::Daily => Some(NANOS_PER_DAY),
+            DigestFrequency::Weekly => Some(7 * NANOS_PER_DAY),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            DigestFrequency::Off => "",
+            DigestFrequency::Daily => "daily",
+            DigestFrequency::Weekly => "weekly",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct TopPost {
+    pub post_id: u64,
+    /// Likes received during the period
+    pub likes: u32,
+}
+
+/// What happened to a user during one digest period
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DigestSummary {
+    pub frequency: DigestFrequency,
+    pub period_start: u64,
+    pub period_end: u64,
+    pub new_followers: u32,
+    pub likes: u32,
+    pub comments: u32,
+    pub reposts: u32,
+    pub top_post: Option<TopPost>,
+    /// Unread messages when the digest was sent
+    pub unread_messages: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Tally {
+    new_followers: u32,
+    likes: u32,
+    comments: u32,
+    reposts: u32,
+    likes_per_post: BTreeMap<u64, u32>,
+    /// What was counted this period, so an unlike or unfollow only undoes
+    /// something that is actually in the tally
+    counted: BTreeSet<(Principal, NotificationType, NotificationTarget)>,
+}
+
+#[derive(Clone, Debug)]
+struct Subscription {
+    frequency: DigestFrequency,
+    period_start: u64,
+    tally: Tally,
+}
+
+impl Subscription {
+    fn due_at(&self) -> u64 {
+        self.period_start + self.frequency.period().unwrap_or(0)
+    }
+}
+
+// Storage
+thread_local! {
+    static SUBSCRIPTIONS: RefCell<BTreeMap<Principal, Subscription>> = const { RefCell::new(BTreeMap::new()) };
+    // (due at, user)
+    static DUE: RefCell<BTreeSet<(u64, Principal)>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Helpers
+
+fn count(n: u32, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+fn adjust(
+    sender: Principal,
+    receiver: Principal,
+    notification_type: NotificationType,
+    target: NotificationTarget,
+    undo: bool,
+) {
+    SUBSCRIPTIONS.with(|s| {
+        let mut s = s.borrow_mut();
+        let Some(tally) = s.get_mut(&receiver).map(|sub| &mut sub.tally) else { return };
+        let key = (sender, notification_type, target);
+        let changed = if undo { tally.counted.remove(&key) } else { tally.counted.insert(key) };
+        if !changed {
+            return;
+        }
+        let apply = |n: &mut u32| *n = if undo { n.saturating_sub(1) } else { *n + 1 };
+        match (notification_type, target) {
+            (NotificationType::Follow, _) => apply(&mut tally.new_followers),
+            (NotificationType::Like, NotificationTarget::PostId(post_id)) => {
+                apply(&mut tally.likes);
+                let likes = tally.likes_per_post.entry(post_id).or_default();
+                apply(likes);
+                if *likes == 0 {
+                    tally.likes_per_post.remove(&post_id);
+                }
+            }
+            (NotificationType::Comment, _) => apply(&mut tally.comments),
+            (NotificationType::Repost, _) => apply(&mut tally.reposts),
+            _ => {}
+        }
+    });
+}
+
+fn summarize(sub: &Subscription, user: Principal, now: u64) -> DigestSummary {
+    let tally = &sub.tally;
+    // most liked post that still exists; ties go to the newer post
+    let top_post = tally
+        .likes_per_post
+        .iter()
+        .filter(|(post_id, _)| POSTS.with(|p| p.borrow().contains_key(post_id)))
+        .max_by_key(|(post_id, likes)| (**likes, **post_id))
+        .map(|(post_id, likes)| TopPost { post_id: *post_id, likes: *likes });
+    DigestSummary {
+        frequency: sub.frequency,
+        period_start: sub.period_start,
+        period_end: now,
+        new_followers: tally.new_followers,
+        likes: tally.likes,
+        comments: tally.comments,
+        reposts: tally.reposts,
+        top_post,
+        unread_messages: messaging::unread_total(user),
+    }
+}
+
+/// e.g. "Your weekly digest: 12 new followers, your top post got 80 likes, 5 unread messages"
+fn describe(summary: &DigestSummary) -> Option<String> {
+    let mut parts = Vec::new();
+    if summary.new_followers > 0 {
+        parts.push(count(summary.new_followers, "new follower", "new followers"));
+    }
+    if let Some(top) = summary.top_post {
+        parts.push(format!("your top post got {}", count(top.likes, "like", "likes")));
+    }
+    if summary.comments > 0 {
+        parts.push(count(summary.comments, "new comment", "new comments"));
+    }
+    if summary.reposts > 0 {
+        parts.push(count(summary.reposts, "repost", "reposts"));
+    }
+    if summary.unread_messages > 0 {
+        let unread = summary.unread_messages.min(u32::MAX as u64) as u32;
+        parts.push(count(unread, "unread message", "unread messages"));
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    Some(format!("Your {} digest: {}", summary.frequency.label(), parts.join(", ")))
+}
+
+/// Send `user` their digest, skipped if nothing happened, and start the next period
+fn send_digest(user: Principal, now: u64) {
+    let Some(sub) = SUBSCRIPTIONS.with(|s| s.borrow().get(&user).cloned()) else { return };
+    let summary = summarize(&sub, user, now);
+    if let Some(message) = describe(&summary) {
+        store_notification(
+            ic_cdk::api::id(),
+            user,
+            NotificationType::Digest,
+            message,
+            NotificationTarget::Profile(user),
+            Some(summary),
+        );
+    }
+
+    let next = Subscription { frequency: sub.frequency, period_start: now, tally: Tally::default() };
+    DUE.with(|d| {
+        let mut d = d.borrow_mut();
+        d.remove(&(sub.due_at(), user));
+        d.insert((next.due_at(), user));
+    });
+    SUBSCRIPTIONS.with(|s| s.borrow_mut().insert(user, next));
+}
+
+// Internal API
+
+/// Count a notification towards the receiver's digest, if they get one
+pub(crate) fn record(
+    sender: Principal,
+    receiver: Principal,
+    notification_type: NotificationType,
+    target: NotificationTarget,
+) {
+    adjust(sender, receiver, notification_type, target, false);
+}
+
+/// Undo `record`, e.g. on unlike or unfollow; a no-op unless it was counted
+/// in the current period
+pub(crate) fn unrecord(
+    sender: Principal,
+    receiver: Principal,
+    notification_type: NotificationType,
+    target: NotificationTarget,
+) {
+    adjust(sender, receiver, notification_type, target, true);
+}
+
+/// Send whatever digests have fallen due every `DIGEST_INTERVAL`
+pub fn start_digest_timer() {
+    ic_cdk_timers::set_timer_interval(DIGEST_INTERVAL, || send_due_digests(time()));
+}
+
+fn send_due_digests(now: u64) {
+    let due: Vec<Principal> = DUE.with(|d| {
+        d.borrow()
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .take(MAX_DIGEST_BATCH)
+            .map(|(_, user)| *user)
+            .collect()
+    });
+    for user in due {
+        send_digest(user, now);
+    }
+}
+
+// Endpoints
+
+/// Opt in to a daily or weekly digest, or turn it off. Changing the frequency
+/// keeps what has been counted so far in the current period.
+#[ic_cdk::update]
+pub fn set_digest_frequency(frequency: DigestFrequency) -> Result<DigestFrequency, String> {
+    let me = auth::registered_caller()?;
+    let current = SUBSCRIPTIONS.with(|s| s.borrow_mut().remove(&me));
+    if let Some(sub) = &current {
+        DUE.with(|d| d.borrow_mut().remove(&(sub.due_at(), me)));
+    }
+    events::record_for(me, EventKind::SettingChanged { user: me, setting: Setting::DigestFrequency });
+    if frequency == DigestFrequency::Off {
+        return Ok(frequency);
+    }
+
+    let sub = match current {
+        Some(sub) => Subscription { frequency, ..sub },
+        None => Subscription { frequency, period_start: time(), tally: Tally::default() },
+    };
+    DUE.with(|d| d.borrow_mut().insert((sub.due_at(), me)));
+    SUBSCRIPTIONS.with(|s| s.borrow_mut().insert(me, sub));
+    Ok(frequency)
+}
+
+#[ic_cdk::query]
+pub fn get_digest_frequency() -> DigestFrequency {
+    let me = caller();
+    SUBSCRIPTIONS.with(|s| s.borrow().get(&me).map(|sub| sub.frequency).unwrap_or_default())
+}
@@ -0,0 +1,142 @@
+// Public key directory and end-to-end encrypted message payloads.
+//
+// The canister never sees plaintext or private keys. Clients publish an
+// encryption public key, encrypt a message once with a fresh content key,
+// and wrap that content key for every participant's published key. The
+// canister only stores the opaque bytes and checks their shape and size.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::events::{self, EventKind};
+use crate::{auth, validation};
+
+pub const MIN_PUBLIC_KEY_BYTES: usize = 32;
+pub const MAX_PUBLIC_KEY_BYTES: usize = 1_024;
+pub const MAX_CIPHERTEXT_BYTES: usize = 16 * 1_024;
+pub const MAX_NONCE_BYTES: usize = 64;
+pub const MAX_WRAPPED_KEY_BYTES: usize = 512;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EncryptionKey {
+    pub key_id: u32,
+    pub user_principal: Principal,
+    pub algorithm: String,
+    pub public_key: Vec<u8>,
+    pub created_at: u64,
+    /// Set once a newer key replaces this one
+    pub rotated_at: Option<u64>,
+}
+
+/// The message content key, wrapped for one recipient's public key
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WrappedKey {
+    pub recipient: Principal,
+    pub key_id: u32,
+    pub wrapped_key: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EncryptedPayload {
+    pub algorithm: String,
+    pub ciphertext: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub recipients: Vec<WrappedKey>,
+}
+
+thread_local! {
+    // Every key a user has published, oldest first; the last one is current
+    static KEYS: RefCell<BTreeMap<Principal, Vec<EncryptionKey>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Id and algorithm of the user's current key
+fn current_key(user: Principal) -> Option<(u32, String)> {
+    KEYS.with(|k| k.borrow().get(&user).and_then(|keys| keys.last()).map(|key| (key.key_id, key.algorithm.clone())))
+}
+
+fn check_bytes(field: &str, bytes: &[u8], min: usize, max: usize) -> Result<(), String> {
+    if bytes.len() < min || bytes.len() > max {
+        return Err(format!("{} must be {}-{} bytes (got {})", field, min, max, bytes.len()));
+    }
+    Ok(())
+}
+
+/// Check an encrypted payload for a conversation with exactly `members`:
+/// every member needs a wrapped key made for their current key, and that key
+/// must use the payload's algorithm. Rotated keys are not accepted.
+pub fn check_payload(payload: &EncryptedPayload, members: &[Principal]) -> Result<(), String> {
+    let algorithm = validation::clean_required_text(&payload.algorithm, &validation::KEY_ALGORITHM)?;
+    check_bytes("ciphertext", &payload.ciphertext, 1, MAX_CIPHERTEXT_BYTES)?;
+    check_bytes("nonce", &payload.nonce, 1, MAX_NONCE_BYTES)?;
+
+    if payload.recipients.len() != members.len() {
+        return Err("Encrypted message needs exactly one wrapped key per participant".to_string());
+    }
+    for member in members {
+        let wrapped = payload
+            .recipients
+            .iter()
+            .find(|w| w.recipient == *member)
+            .ok_or_else(|| format!("Missing wrapped key for {}", member))?;
+        let (key_id, key_algorithm) =
+            current_key(*member).ok_or_else(|| format!("{} has not published an encryption key", member))?;
+        if wrapped.key_id != key_id {
+            return Err(format!("Encryption key {} is not the current key of {}", wrapped.key_id, member));
+        }
+        if algorithm != key_algorithm {
+            return Err(format!("{} uses {}, not {}", member, key_algorithm, algorithm));
+        }
+        check_bytes("wrapped_key", &wrapped.wrapped_key, 1, MAX_WRAPPED_KEY_BYTES)?;
+        check_bytes("wrapped key nonce", &wrapped.nonce, 1, MAX_NONCE_BYTES)?;
+    }
+    Ok(())
+}
+
+/// Publish a new encryption public key. The previous key stays listed,
+/// marked as rotated, so older messages can still be decrypted.
+#[ic_cdk::update]
+pub fn publish_encryption_key(algorithm: String, public_key: Vec<u8>) -> Result<EncryptionKey, String> {
+    let principal = auth::registered_caller()?;
+    let algorithm = validation::clean_required_text(&algorithm, &validation::KEY_ALGORITHM)?;
+    check_bytes("public_key", &public_key, MIN_PUBLIC_KEY_BYTES, MAX_PUBLIC_KEY_BYTES)?;
+
+    let key = KEYS.with(|k| {
+        let mut k = k.borrow_mut();
+        let keys = k.entry(principal).or_default();
+        if keys.last().map(|key| key.public_key == public_key).unwrap_or(false) {
+            return Err("This key is already published".to_string());
+        }
+
+        let now = time();
+        if let Some(current) = keys.last_mut() {
+            current.rotated_at = Some(now);
+        }
+        let key = EncryptionKey {
+            key_id: keys.len() as u32 + 1,
+            user_principal: principal,
+            algorithm,
+            public_key,
+            created_at: now,
+            rotated_at: None,
+        };
+        keys.push(key.clone());
+        Ok(key)
+    })?;
+    events::record(EventKind::EncryptionKeyPublished { user: principal, key_id: key.key_id });
+    Ok(key)
+}
+
+/// The key other users should encrypt to
+#[ic_cdk::query]
+pub fn get_encryption_key(user_principal: Principal) -> Option<EncryptionKey> {
+    KEYS.with(|k| k.borrow().get(&user_principal).and_then(|keys| keys.last().cloned()))
+}
+
+/// All keys a user has published, including rotated ones
+#[ic_cdk::query]
+pub fn get_encryption_keys(user_principal: Principal) -> Vec<EncryptionKey> {
+    KEYS.with(|k| k.borrow().get(&user_principal).cloned().unwrap_or_default())
+}
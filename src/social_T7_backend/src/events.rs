@@ -0,0 +1,180 @@
+// Append-only log of state changes.
+//
+// Each mutation made through an endpoint appends a typed event under the next
+// global sequence number; nothing is ever rewritten or removed. Indexers and
+// third-party clients replay it with `get_events`. Events about
+// conversations, blocks, settings and notifications are private and only
+// returned to the people involved; admin changes only to admins.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, time};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::auth;
+
+pub const MAX_EVENT_PAGE: u32 = 200;
+/// Events looked at per call, so a reader who can see few of them still gets a bounded call
+pub const MAX_EVENT_SCAN: usize = 2_000;
+
+/// Events carry ids only, never content: the log is public and permanent,
+/// so deleted or edited text must not live on in it. Readers fetch the
+/// current state instead.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum EventKind {
+    UserRegistered { user: Principal },
+    ProfileUpdated { user: Principal },
+    PostCreated { post_id: u64, author: Principal },
+    PostEdited { post_id: u64 },
+    PostDeleted { post_id: u64 },
+    Reposted { post_id: u64, original_post_id: u64, by: Principal },
+    PostLiked { post_id: u64, by: Principal },
+    PostUnliked { post_id: u64, by: Principal },
+    CommentAdded { post_id: u64, comment_id: u64, author: Principal },
+    Followed { follower: Principal, followee: Principal },
+    Unfollowed { follower: Principal, followee: Principal },
+    Blocked { blocker: Principal, blocked: Principal },
+    Unblocked { blocker: Principal, blocked: Principal },
+    MessageSent { message_id: u64, conversation_id: u64, from: Principal },
+    MessageEdited { message_id: u64, conversation_id: u64 },
+    MessageUnsent { message_id: u64, conversation_id: u64 },
+    MessageExpired { message_id: u64, conversation_id: u64 },
+    ReactionAdded { message_id: u64, conversation_id: u64, by: Principal },
+    ReactionRemoved { message_id: u64, conversation_id: u64, by: Principal },
+    MessageRequestAccepted { conversation_id: u64, by: Principal, from: Principal },
+    MessageRequestDeclined { conversation_id: u64, by: Principal, from: Principal },
+    GroupCreated { conversation_id: u64, created_by: Principal },
+    GroupMembersAdded { conversation_id: u64, added_by: Principal, members: Vec<Principal> },
+    GroupMemberRemoved { conversation_id: u64, removed_by: Principal, member: Principal },
+    GroupLeft { conversation_id: u64, member: Principal },
+    GroupAdminChanged { conversation_id: u64, changed_by: Principal, member: Principal, admin: bool },
+    DisappearingMessagesChanged { conversation_id: u64, by: Principal },
+    MessageDeletedForMe { message_id: u64, conversation_id: u64, by: Principal },
+    ConversationRead { conversation_id: u64, by: Principal, up_to: u64 },
+    MessagesDelivered { conversation_id: u64, by: Principal, up_to: u64 },
+    ConversationFiled { conversation_id: u64, by: Principal, change: ConversationChange },
+    AttachmentUploaded { attachment_id: u64, by: Principal },
+    AttachmentDeleted { attachment_id: u64, by: Principal },
+    EncryptionKeyPublished { user: Principal, key_id: u32 },
+    SettingChanged { user: Principal, setting: Setting },
+    NotificationsRead { user: Principal, notification_ids: Vec<u64> },
+    NotificationsDeleted { user: Principal, notification_ids: Vec<u64> },
+    InviteCreated { by: Principal },
+    InviteRedeemed { by: Principal, invited_by: Principal },
+    RegistrationRequested { user: Principal },
+    RegistrationApproved { user: Principal },
+    RegistrationRejected { user: Principal },
+    AdminSettingChanged { by: Principal, setting: AdminSetting },
+}
+
+/// How one member filed a conversation for themselves
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum ConversationChange {
+    Archived,
+    Unarchived,
+    Muted,
+    Unmuted,
+    Pinned,
+    Unpinned,
+    Deleted,
+}
+
+/// A user's own setting; fetch it for the new value
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum Setting {
+    DmPolicy,
+    ReadReceipts,
+    NotificationPreferences,
+    DigestFrequency,
+    FeedWeights,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum AdminSetting {
+    RegistrationMode,
+    InviteQuota { user: Principal },
+    FeedWeights,
+    NotificationRetention,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub kind: EventKind,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    /// Pass as `from_seq` to continue
+    pub next_seq: u64,
+}
+
+#[derive(Clone, Debug)]
+struct StoredEvent {
+    event: Event,
+    /// Who may read it; `None` for public events
+    audience: Option<Vec<Principal>>,
+}
+
+// Storage
+thread_local! {
+    static EVENTS: RefCell<BTreeMap<u64, StoredEvent>> = const { RefCell::new(BTreeMap::new()) };
+    static EVENT_SEQUENCE: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// Internal API
+
+fn append(kind: EventKind, audience: Option<Vec<Principal>>) {
+    let seq = EVENT_SEQUENCE.with(|s| {
+        let mut s = s.borrow_mut();
+        *s += 1;
+        *s
+    });
+    let event = Event { seq, timestamp: time(), kind };
+    EVENTS.with(|e| e.borrow_mut().insert(seq, StoredEvent { event, audience }));
+}
+
+/// Log an event anyone may read
+pub(crate) fn record(kind: EventKind) {
+    append(kind, None);
+}
+
+/// Log an event only `audience` (and admins) may read
+pub(crate) fn record_private(kind: EventKind, audience: Vec<Principal>) {
+    append(kind, Some(audience));
+}
+
+/// Log an event only `user` (and admins) may read
+pub(crate) fn record_for(user: Principal, kind: EventKind) {
+    append(kind, Some(vec![user]));
+}
+
+// Endpoints
+
+/// Events with `seq >= from_seq`, oldest first. Sequence numbers start at 1
+/// and have no gaps, though private events of others are left out.
+#[ic_cdk::query]
+pub fn get_events(from_seq: u64, limit: u32) -> EventPage {
+    let me = caller();
+    let admin = auth::admin_caller().is_ok();
+    let limit = limit.clamp(1, MAX_EVENT_PAGE) as usize;
+
+    EVENTS.with(|e| {
+        let e = e.borrow();
+        let mut events = Vec::new();
+        let mut next_seq = from_seq;
+        for (seq, stored) in e.range(from_seq..).take(MAX_EVENT_SCAN) {
+            if events.len() == limit {
+                break;
+            }
+            next_seq = seq + 1;
+            let visible = admin || stored.audience.as_ref().map(|a| a.contains(&me)).unwrap_or(true);
+            if visible {
+                events.push(stored.event.clone());
+            }
+        }
+        EventPage { events, next_seq }
+    })
+}
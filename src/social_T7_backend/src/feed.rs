@@ -0,0 +1,384 @@
+// Home feed: a ranked "For You" mode and a strictly chronological "Following" mode.
+//
+// For You scores the home timeline and the newest posts on five signals,
+// each in 0..=1 before weighting: recency (halving every `half_life_hours`),
+// engagement (likes, comments and reposts, log-scaled), affinity (how often
+// the viewer interacted with the author before), network proximity (followed
+// author, reposted or liked by someone followed, or followed by someone
+// followed) and trending (uses a hashtag many recent posts use). Following
+// reads the precomputed home timeline.
+//
+// Nothing is hidden: every item carries its signal values and the reasons
+// behind them, `get_feed_weights` shows the parameters in use, and users can
+// replace the default weights with their own.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, time};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::events::{self, AdminSetting, EventKind, Setting};
+use crate::timeline::{self, TIMELINE_CAPACITY};
+use crate::{auth, is_blocked_either, search, Post, POSTS, USERS};
+
+/// Posts returned per feed call
+pub const FEED_PAGE: usize = 50;
+/// Most recent posts from anyone added to the home timeline as For You candidates
+pub const MAX_DISCOVERY: usize = 200;
+pub const MAX_WEIGHT: f64 = 100.0;
+/// A hashtag is trending when this many candidate posts from the window use it
+pub const TRENDING_MIN_POSTS: usize = 3;
+pub const TRENDING_WINDOW_HOURS: u64 = 24;
+/// People listed on a "liked by" or "reposted by" reason
+pub const MAX_REASON_USERS: usize = 3;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FeedMode {
+    ForYou,
+    /// Posts by people you follow and your own, newest first
+    #[default]
+    Following,
+}
+
+/// How For You combines its signals
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct FeedWeights {
+    pub recency: f64,
+    pub engagement: f64,
+    pub affinity: f64,
+    pub proximity: f64,
+    pub trending: f64,
+    /// Age at which the recency signal has halved
+    pub half_life_hours: f64,
+}
+
+impl FeedWeights {
+    fn check(&self) -> Result<(), String> {
+        let weights = [self.recency, self.engagement, self.affinity, self.proximity, self.trending];
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0 || *w > MAX_WEIGHT) {
+            return Err(format!("Weights must be between 0 and {}", MAX_WEIGHT));
+        }
+        if weights.iter().all(|w| *w == 0.0) {
+            return Err("At least one weight must be above 0".to_string());
+        }
+        if !self.half_life_hours.is_finite() || self.half_life_hours < 1.0 || self.half_life_hours > 24.0 * 30.0 {
+            return Err("half_life_hours must be between 1 and 720".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The weights in use for the caller and the fixed limits around them
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeedParameters {
+    pub weights: FeedWeights,
+    /// The caller set their own weights instead of the defaults
+    pub custom: bool,
+    pub defaults: FeedWeights,
+    pub page_size: u32,
+    pub timeline_capacity: u32,
+    pub discovery_posts: u32,
+    pub trending_min_posts: u32,
+    pub trending_window_hours: u32,
+}
+
+/// Why a post is in the feed
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum FeedReason {
+    OwnPost,
+    FollowedAuthor,
+    /// People you follow who reposted it, at most `MAX_REASON_USERS`
+    RepostedBy { users: Vec<Principal>, count: u32 },
+    LikedByFollowing { users: Vec<Principal>, count: u32 },
+    /// Someone you follow follows the author
+    FollowedByFollowing { user: Principal },
+    /// You liked, commented on or reposted the author's posts before
+    InteractedWithAuthor { interactions: u32 },
+    TrendingTag { tag: String },
+    /// One of the newest posts on the platform, with no other connection to you
+    Recent,
+}
+
+/// Signal values of a ranked post, each in 0..=1, and the weighted total
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct FeedScores {
+    pub recency: f64,
+    pub engagement: f64,
+    pub affinity: f64,
+    pub proximity: f64,
+    pub trending: f64,
+    pub total: f64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeedItem {
+    pub post: Post,
+    /// `None` in the chronological Following mode
+    pub scores: Option<FeedScores>,
+    pub reasons: Vec<FeedReason>,
+}
+
+/// Who else touched a post, gathered while collecting candidates
+#[derive(Default)]
+struct Signals {
+    reposts: u32,
+    /// Followed reposters, most recent first
+    reposted_by_following: Vec<Principal>,
+}
+
+/// What ranking needs to know about the viewer
+struct Context {
+    me: Principal,
+    following: BTreeSet<Principal>,
+    weights: FeedWeights,
+    trending: BTreeSet<String>,
+    now: u64,
+}
+
+const DEFAULT_WEIGHTS: FeedWeights =
+    FeedWeights { recency: 1.0, engagement: 1.0, affinity: 1.0, proximity: 1.0, trending: 0.5, half_life_hours: 24.0 };
+
+// Storage
+thread_local! {
+    static WEIGHTS: RefCell<FeedWeights> = const { RefCell::new(DEFAULT_WEIGHTS) };
+    static USER_WEIGHTS: RefCell<BTreeMap<Principal, FeedWeights>> = const { RefCell::new(BTreeMap::new()) };
+    // (viewer, author) -> likes, comments and reposts by the viewer on the author's posts
+    static AFFINITY: RefCell<BTreeMap<(Principal, Principal), u32>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Internal API
+
+/// Count an interaction of `viewer` with a post by `author`; -1 undoes one, e.g. on unlike
+pub(crate) fn record_interaction(viewer: Principal, author: Principal, delta: i32) {
+    if viewer == author {
+        return;
+    }
+    AFFINITY.with(|a| {
+        let mut a = a.borrow_mut();
+        let count = a.entry((viewer, author)).or_default();
+        *count = count.saturating_add_signed(delta);
+        if *count == 0 {
+            a.remove(&(viewer, author));
+        }
+    });
+}
+
+// Helpers
+
+fn following_of(user: Principal) -> BTreeSet<Principal> {
+    USERS.with(|u| u.borrow().get(&user).map(|p| p.following.iter().copied().collect()).unwrap_or_default())
+}
+
+fn custom_weights(user: Principal) -> Option<FeedWeights> {
+    USER_WEIGHTS.with(|w| w.borrow().get(&user).copied())
+}
+
+fn active_weights(user: Principal) -> FeedWeights {
+    custom_weights(user).unwrap_or_else(|| WEIGHTS.with(|w| *w.borrow()))
+}
+
+fn following_feed(me: Principal, before: Option<u64>) -> Vec<FeedItem> {
+    timeline::load(me, &timeline::recent(me, before, TIMELINE_CAPACITY), FEED_PAGE)
+        .into_iter()
+        .map(|post| {
+            let reason = if post.author == me { FeedReason::OwnPost } else { FeedReason::FollowedAuthor };
+            FeedItem { post, scores: None, reasons: vec![reason] }
+        })
+        .collect()
+}
+
+/// log-scaled into 0..=1, reaching 1 at `full`
+fn log_scale(value: f64, full: f64) -> f64 {
+    ((1.0 + value).ln() / (1.0 + full).ln()).min(1.0)
+}
+
+/// Hashtags used by at least `TRENDING_MIN_POSTS` of the recent `posts`
+fn trending_tags(posts: &[&Post], now: u64) -> BTreeSet<String> {
+    let since = now.saturating_sub(TRENDING_WINDOW_HOURS * NANOS_PER_HOUR);
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for post in posts.iter().filter(|p| p.created_at >= since && p.original_post_id.is_none()) {
+        for tag in search::hashtags(&post.content) {
+            *counts.entry(tag).or_default() += 1;
+        }
+    }
+    counts.into_iter().filter(|(_, n)| *n >= TRENDING_MIN_POSTS).map(|(tag, _)| tag).collect()
+}
+
+/// Score a post and say why it scored as it did
+fn rank(ctx: &Context, post: Post, signals: &Signals) -> FeedItem {
+    let weights = &ctx.weights;
+    let mut reasons = Vec::new();
+
+    let age_hours = ctx.now.saturating_sub(post.created_at) as f64 / NANOS_PER_HOUR as f64;
+    let recency = 0.5f64.powf(age_hours / weights.half_life_hours);
+
+    let interactions = post.likes.len() + 2 * post.comments.len() + 3 * signals.reposts as usize;
+    let engagement = log_scale(interactions as f64, 100.0);
+
+    let past = AFFINITY.with(|a| a.borrow().get(&(ctx.me, post.author)).copied().unwrap_or(0));
+    let affinity = log_scale(past as f64, 20.0);
+
+    let liked_by: Vec<Principal> = post.likes.iter().rev().filter(|p| ctx.following.contains(p)).copied().collect();
+    let followed_by = || {
+        USERS.with(|u| {
+            let u = u.borrow();
+            let author = u.get(&post.author)?;
+            author.followers.iter().find(|f| ctx.following.contains(f)).copied()
+        })
+    };
+    let proximity = if post.author == ctx.me {
+        reasons.push(FeedReason::OwnPost);
+        1.0
+    } else if ctx.following.contains(&post.author) {
+        reasons.push(FeedReason::FollowedAuthor);
+        1.0
+    } else if !signals.reposted_by_following.is_empty() || !liked_by.is_empty() {
+        0.5
+    } else if let Some(user) = followed_by() {
+        reasons.push(FeedReason::FollowedByFollowing { user });
+        0.25
+    } else {
+        0.0
+    };
+    // listed for followed authors too, as they say who else vouched for it
+    if !signals.reposted_by_following.is_empty() {
+        let users = &signals.reposted_by_following;
+        reasons.push(FeedReason::RepostedBy {
+            users: users.iter().take(MAX_REASON_USERS).copied().collect(),
+            count: users.len() as u32,
+        });
+    }
+    if !liked_by.is_empty() {
+        reasons.push(FeedReason::LikedByFollowing {
+            users: liked_by.iter().take(MAX_REASON_USERS).copied().collect(),
+            count: liked_by.len() as u32,
+        });
+    }
+    if past > 0 {
+        reasons.push(FeedReason::InteractedWithAuthor { interactions: past });
+    }
+
+    let trending_tag = search::hashtags(&post.content).into_iter().find(|tag| ctx.trending.contains(tag));
+    let trending = if trending_tag.is_some() { 1.0 } else { 0.0 };
+    if let Some(tag) = trending_tag {
+        reasons.push(FeedReason::TrendingTag { tag });
+    }
+    if reasons.is_empty() {
+        reasons.push(FeedReason::Recent);
+    }
+
+    let total = weights.recency * recency
+        + weights.engagement * engagement
+        + weights.affinity * affinity
+        + weights.proximity * proximity
+        + weights.trending * trending;
+    let scores = FeedScores { recency, engagement, affinity, proximity, trending, total };
+    FeedItem { post, scores: Some(scores), reasons }
+}
+
+fn for_you_feed(me: Principal) -> Vec<FeedItem> {
+    let now = time();
+    let following = following_of(me);
+
+    // the home timeline plus the newest posts from anyone (post ids grow over
+    // time); a repost stands in for its original, which is looked up if older
+    let mut seen: BTreeSet<u64> = timeline::recent(me, None, TIMELINE_CAPACITY).into_iter().collect();
+    POSTS.with(|posts| seen.extend(posts.borrow().keys().rev().take(MAX_DISCOVERY)));
+
+    let mut candidates: BTreeMap<u64, Post> = BTreeMap::new();
+    let mut signals: BTreeMap<u64, Signals> = BTreeMap::new();
+    let trending = POSTS.with(|posts| {
+        let posts = posts.borrow();
+        let seen_posts: Vec<&Post> = seen.iter().filter_map(|id| posts.get(id)).collect();
+        // newest first, so reposters are listed most recent first
+        for post in seen_posts.iter().rev() {
+            let Some(original_id) = post.original_post_id else {
+                candidates.entry(post.post_id).or_insert_with(|| (*post).clone());
+                continue;
+            };
+            let s = signals.entry(original_id).or_default();
+            s.reposts += 1;
+            if following.contains(&post.author) {
+                s.reposted_by_following.push(post.author);
+            }
+            if let Some(original) = posts.get(&original_id) {
+                candidates.entry(original_id).or_insert_with(|| original.clone());
+            }
+        }
+        trending_tags(&seen_posts, now)
+    });
+
+    let ctx = Context { me, following, weights: active_weights(me), trending, now };
+    let none = Signals::default();
+    let mut ranked: Vec<FeedItem> = candidates
+        .into_values()
+        .filter(|post| post.author == me || !is_blocked_either(me, post.author))
+        .map(|post| {
+            let s = signals.get(&post.post_id).unwrap_or(&none);
+            rank(&ctx, post, s)
+        })
+        .collect();
+    let total = |item: &FeedItem| item.scores.map(|s| s.total).unwrap_or(0.0);
+    ranked.sort_by(|a, b| total(b).total_cmp(&total(a)).then(b.post.created_at.cmp(&a.post.created_at)));
+    ranked.truncate(FEED_PAGE);
+    ranked
+}
+
+// Endpoints
+
+/// The caller's home feed; Following unless `mode` says otherwise.
+/// Following pages with `before`, the last post id of the previous page;
+/// For You is ranked afresh on each call and ignores it.
+#[ic_cdk::query]
+pub fn get_feed(mode: Option<FeedMode>, before: Option<u64>) -> Vec<FeedItem> {
+    let me = caller();
+    match mode.unwrap_or_default() {
+        FeedMode::ForYou => for_you_feed(me),
+        FeedMode::Following => following_feed(me, before),
+    }
+}
+
+/// The ranking parameters in effect for the caller
+#[ic_cdk::query]
+pub fn get_feed_weights() -> FeedParameters {
+    let me = caller();
+    FeedParameters {
+        weights: active_weights(me),
+        custom: custom_weights(me).is_some(),
+        defaults: WEIGHTS.with(|w| *w.borrow()),
+        page_size: FEED_PAGE as u32,
+        timeline_capacity: TIMELINE_CAPACITY as u32,
+        discovery_posts: MAX_DISCOVERY as u32,
+        trending_min_posts: TRENDING_MIN_POSTS as u32,
+        trending_window_hours: TRENDING_WINDOW_HOURS as u32,
+    }
+}
+
+/// Rank the caller's For You feed with their own weights; `None` goes back to the defaults
+#[ic_cdk::update]
+pub fn set_my_feed_weights(weights: Option<FeedWeights>) -> Result<FeedWeights, String> {
+    let me = auth::registered_caller()?;
+    match weights {
+        Some(weights) => {
+            weights.check()?;
+            USER_WEIGHTS.with(|w| w.borrow_mut().insert(me, weights));
+        }
+        None => {
+            USER_WEIGHTS.with(|w| w.borrow_mut().remove(&me));
+        }
+    }
+    events::record_for(me, EventKind::SettingChanged { user: me, setting: Setting::FeedWeights });
+    Ok(active_weights(me))
+}
+
+/// Change the default weights, used by everyone without their own
+#[ic_cdk::update]
+pub fn set_feed_weights(weights: FeedWeights) -> Result<FeedWeights, String> {
+    let admin = auth::admin_caller()?;
+    weights.check()?;
+    WEIGHTS.with(|w| *w.borrow_mut() = weights);
+    events::record_private(EventKind::AdminSettingChanged { by: admin, setting: AdminSetting::FeedWeights }, Vec::new());
+    Ok(weights)
+}
@@ -1,23 +1,46 @@
+// The crate name has to match the dfx canister name
+#![allow(non_snake_case)]
+
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::{caller, time};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+
+mod attachments;
+mod auth;
+mod digest;
+mod encryption;
+mod events;
+mod feed;
+mod messaging;
+mod notifications;
+mod search;
+mod sync;
+mod timeline;
+mod validation;
+
+use attachments::{Attachment, AttachmentKind};
+use auth::{Invite, PendingRegistration, RegistrationMode};
+use digest::DigestFrequency;
+use encryption::{EncryptedPayload, EncryptionKey};
+use events::{EventKind, EventPage};
+use feed::{FeedItem, FeedMode, FeedParameters, FeedWeights};
+use messaging::{
+    Conversation, DateRange, DisappearAfter, DmAccess, DmPolicy, InboxCursor, InboxPage, Message, MessageSearchPage,
+};
+use notifications::{
+    add_notification_internal, retract_notification, Notification, NotificationGroupCursor, NotificationGroupPage,
+    NotificationPreferences, NotificationTarget, NotificationType, RetentionPolicy,
+};
+use sync::{Change, SyncUpdates};
+use validation::MediaKind;
 
 // ---------- Candid interface export ----------
 candid::export_service!();
 
 // Data Structures
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub struct Message {
-    pub id: u64,
-    pub from: Principal,
-    pub to: Principal,
-    pub content: String,
-    pub created_at: u64,
-    pub seen: bool,
-}
-
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct UserProfile {
     pub user_principal: Principal,
@@ -52,53 +75,25 @@ pub struct Comment {
     pub created_at: u64,
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub struct Notification {
-    pub notification_id: u64,
-    pub sender: Principal,
-    pub receiver: Principal,
-    pub notification_type: NotificationType,
-    pub message: String,
-    pub created_at: u64,
-    pub read: bool,
-}
-
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum NotificationType {
-    Like,
-    Comment,
-    Follow,
-    Repost,
-    Message,
-}
-
 // Storage
 thread_local! {
     // Core app storages
-    static USERS: RefCell<BTreeMap<Principal, UserProfile>> = RefCell::new(BTreeMap::new());
-    static POSTS: RefCell<BTreeMap<u64, Post>> = RefCell::new(BTreeMap::new());
-    static NOTIFICATIONS: RefCell<BTreeMap<u64, Notification>> = RefCell::new(BTreeMap::new());
+    static USERS: RefCell<BTreeMap<Principal, UserProfile>> = const { RefCell::new(BTreeMap::new()) };
+    static POSTS: RefCell<BTreeMap<u64, Post>> = const { RefCell::new(BTreeMap::new()) };
+    // (blocker, blocked)
+    static BLOCKS: RefCell<BTreeSet<(Principal, Principal)>> = const { RefCell::new(BTreeSet::new()) };
 
-    static POST_COUNTER: RefCell<u64> = RefCell::new(0);
-    static COMMENT_COUNTER: RefCell<u64> = RefCell::new(0);
-    static NOTIFICATION_COUNTER: RefCell<u64> = RefCell::new(0);
-
-    // Messaging
-    static MESSAGES: RefCell<BTreeMap<(Principal, Principal), Vec<Message>>> = RefCell::new(BTreeMap::new());
-    static MESSAGE_COUNTER: RefCell<u64> = RefCell::new(0);
+    static POST_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static COMMENT_COUNTER: RefCell<u64> = const { RefCell::new(0) };
 }
 
 // Helpers
 
-fn convo_key(a: Principal, b: Principal) -> (Principal, Principal) {
-    if a.to_text() <= b.to_text() { (a, b) } else { (b, a) }
-}
-
-fn next_message_id() -> u64 {
-    MESSAGE_COUNTER.with(|c| {
-        let mut m = c.borrow_mut();
-        *m += 1;
-        *m
+/// True if either user has blocked the other
+fn is_blocked_either(a: Principal, b: Principal) -> bool {
+    BLOCKS.with(|b_set| {
+        let b_set = b_set.borrow();
+        b_set.contains(&(a, b)) || b_set.contains(&(b, a))
     })
 }
 
@@ -118,39 +113,35 @@ fn get_next_comment_id() -> u64 {
     })
 }
 
-fn get_next_notification_id() -> u64 {
-    NOTIFICATION_COUNTER.with(|counter| {
-        let mut count = counter.borrow_mut();
-        *count += 1;
-        *count
-    })
+// Lifecycle
+
+// Timers don't survive upgrades, so they are started again afterwards
+fn start_timers() {
+    messaging::start_expiry_timer();
+    notifications::start_prune_timer();
+    digest::start_digest_timer();
 }
 
-/// NEW: DM policy — allowed if EITHER side follows the other
-fn can_dm(me: Principal, to: Principal) -> bool {
-    USERS.with(|u| {
-        let u = u.borrow();
-        let me_follows_to = u
-            .get(&me)
-            .map(|me_user| me_user.following.contains(&to))
-            .unwrap_or(false);
-        let to_follows_me = u
-            .get(&to)
-            .map(|to_user| to_user.following.contains(&me))
-            .unwrap_or(false);
-        me_follows_to || to_follows_me
-    })
+#[ic_cdk::init]
+fn init() {
+    start_timers();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    start_timers();
 }
 
 // User Management
 
 #[ic_cdk::update]
 pub fn register_user(name: String, bio: String, profile_image: String, cover_image: String) -> Result<UserProfile, String> {
-    let principal = caller();
+    let principal = auth::authenticated_caller()?;
 
-    if name.trim().is_empty() {
-        return Err("Name cannot be empty".to_string());
-    }
+    let name = validation::clean_required_text(&name, &validation::NAME)?;
+    let bio = validation::clean_text(&bio, &validation::BIO)?;
+    let profile_image = validation::clean_media("profile_image", &profile_image, MediaKind::Image)?;
+    let cover_image = validation::clean_media("cover_image", &cover_image, MediaKind::Image)?;
 
     USERS.with(|users| {
         let mut users = users.borrow_mut();
@@ -158,6 +149,7 @@ pub fn register_user(name: String, bio: String, profile_image: String, cover_ima
         if users.contains_key(&principal) {
             return Err("User already registered".to_string());
         }
+        auth::check_registration_allowed(principal)?;
 
         let user_profile = UserProfile {
             user_principal: principal,
@@ -171,6 +163,8 @@ pub fn register_user(name: String, bio: String, profile_image: String, cover_ima
         };
 
         users.insert(principal, user_profile.clone());
+        auth::registration_completed(principal);
+        events::record(EventKind::UserRegistered { user: principal });
         Ok(user_profile)
     })
 }
@@ -188,7 +182,12 @@ pub fn get_current_user() -> Option<UserProfile> {
 
 #[ic_cdk::update]
 pub fn update_profile(name: String, bio: String, profile_image: String, cover_image: String) -> Result<UserProfile, String> {
-    let principal = caller();
+    let principal = auth::registered_caller()?;
+
+    let name = validation::clean_required_text(&name, &validation::NAME)?;
+    let bio = validation::clean_text(&bio, &validation::BIO)?;
+    let profile_image = validation::clean_media("profile_image", &profile_image, MediaKind::Image)?;
+    let cover_image = validation::clean_media("cover_image", &cover_image, MediaKind::Image)?;
 
     USERS.with(|users| {
         let mut users = users.borrow_mut();
@@ -198,6 +197,7 @@ pub fn update_profile(name: String, bio: String, profile_image: String, cover_im
                 user.bio = bio;
                 user.profile_image = profile_image;
                 user.cover_image = cover_image;
+                events::record(EventKind::ProfileUpdated { user: principal });
                 Ok(user.clone())
             }
             None => Err("User not found".to_string()),
@@ -205,122 +205,20 @@ pub fn update_profile(name: String, bio: String, profile_image: String, cover_im
     })
 }
 
-// Messaging
-
-/// Send a message (allowed if either side follows the other)
-#[ic_cdk::update]
-pub fn send_message(to: Principal, content: String) -> Result<Message, String> {
-    let me = caller();
-    if content.trim().is_empty() { return Err("Message cannot be empty".into()); }
-
-    // both users must exist
-    let (me_exists, to_exists) = USERS.with(|u| {
-        let u = u.borrow();
-        (u.contains_key(&me), u.contains_key(&to))
-    });
-    if !me_exists { return Err("Sender not registered".into()); }
-    if !to_exists { return Err("Receiver not found".into()); }
-
-    // NEW policy: allowed if EITHER side follows the other
-    if !can_dm(me, to) {
-        return Err("You can only message users you follow or who follow you".into());
-    }
-
-    let msg = Message {
-        id: next_message_id(),
-        from: me,
-        to,
-        content,
-        created_at: time(),
-        seen: false,
-    };
-
-    // persist message
-    MESSAGES.with(|mm| {
-        let mut mm = mm.borrow_mut();
-        let key = convo_key(me, to);
-        mm.entry(key).or_default().push(msg.clone());
-    });
-
-    // notify receiver
-    let _ = add_notification_internal(
-        me,
-        to,
-        NotificationType::Message,
-        "sent you a message".to_string(),
-    );
-
-    Ok(msg)
-}
-
-/// Handy: can current caller DM `to`?
-#[ic_cdk::query]
-pub fn can_message(to: Principal) -> bool {
-    can_dm(caller(), to)
-}
-
-/// Get full conversation with someone (sorted by time asc)
-#[ic_cdk::query]
-pub fn get_conversation(with_user: Principal) -> Vec<Message> {
-    let me = caller();
-    MESSAGES.with(|mm| {
-        let key = convo_key(me, with_user);
-        let mut v = mm.borrow().get(&key).cloned().unwrap_or_default();
-        v.sort_by(|a, b| a.created_at.cmp(&b.created_at));
-        v
-    })
-}
-
-/// Mark as seen (all messages FROM `with_user` TO me up to last_id)
-#[ic_cdk::update]
-pub fn mark_seen(with_user: Principal, last_id: u64) -> Result<String, String> {
-    let me = caller();
-    MESSAGES.with(|mm| {
-        let key = convo_key(me, with_user);
-        let mut_map = &mut *mm.borrow_mut();
-        if let Some(list) = mut_map.get_mut(&key) {
-            for m in list.iter_mut() {
-                if m.to == me && m.from == with_user && m.id <= last_id { m.seen = true; }
-            }
-            Ok("seen updated".into())
-        } else {
-            Err("No conversation".into())
-        }
-    })
-}
-
-/// List conversation peers for inbox
-#[ic_cdk::query]
-pub fn get_inbox() -> Vec<Principal> {
-    let me = caller();
-    MESSAGES.with(|mm| {
-        mm.borrow()
-            .keys()
-            .filter_map(|(a, b)| {
-                if *a == me { Some(b.clone()) }
-                else if *b == me { Some(a.clone()) }
-                else { None }
-            })
-            .collect()
-    })
-}
-
 // Posts
 
 #[ic_cdk::update]
 pub fn create_post(content: String, image: Option<String>, video: Option<String>) -> Result<Post, String> {
-    let principal = caller();
+    let principal = auth::registered_caller()?;
+
+    let content = validation::clean_text(&content, &validation::POST_CONTENT)?;
+    let image = validation::clean_optional_media("image", image, MediaKind::Image)?;
+    let video = validation::clean_optional_media("video", video, MediaKind::Video)?;
 
-    if content.trim().is_empty() && image.is_none() && video.is_none() {
+    if content.is_empty() && image.is_none() && video.is_none() {
         return Err("Post must have content, image, or video".to_string());
     }
 
-    // Check if user exists
-    USERS.with(|users| {
-        if !users.borrow().contains_key(&principal) { return Err("User not registered".to_string()); }
-        Ok(())
-    })?;
-
     let post_id = get_next_post_id();
     let post = Post {
         post_id,
@@ -336,6 +234,9 @@ pub fn create_post(content: String, image: Option<String>, video: Option<String>
     };
 
     POSTS.with(|posts| { posts.borrow_mut().insert(post_id, post.clone()); });
+    sync::touch(Change::Post { post_id, author: principal });
+    timeline::on_post_created(principal, post_id);
+    events::record(EventKind::PostCreated { post_id, author: principal });
 
     Ok(post)
 }
@@ -344,7 +245,7 @@ pub fn create_post(content: String, image: Option<String>, video: Option<String>
 pub fn get_all_posts() -> Vec<Post> {
     POSTS.with(|posts| {
         let mut all_posts: Vec<Post> = posts.borrow().values().cloned().collect();
-        all_posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        all_posts.sort_by_key(|p| Reverse(p.created_at));
         all_posts
     })
 }
@@ -358,14 +259,14 @@ pub fn get_user_posts(user_principal: Principal) -> Vec<Post> {
             .filter(|post| post.author == user_principal)
             .cloned()
             .collect();
-        user_posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        user_posts.sort_by_key(|p| Reverse(p.created_at));
         user_posts
     })
 }
 
 #[ic_cdk::update]
 pub fn like_post(post_id: u64) -> Result<Post, String> {
-    let principal = caller();
+    let principal = auth::registered_caller()?;
 
     POSTS.with(|posts| {
         let mut posts = posts.borrow_mut();
@@ -373,18 +274,28 @@ pub fn like_post(post_id: u64) -> Result<Post, String> {
             Some(post) => {
                 if post.likes.contains(&principal) {
                     post.likes.retain(|p| *p != principal);
+                    events::record(EventKind::PostUnliked { post_id, by: principal });
+                    feed::record_interaction(principal, post.author, -1);
+                    if post.author != principal {
+                        let target = NotificationTarget::PostId(post_id);
+                        retract_notification(principal, post.author, NotificationType::Like, target);
+                    }
                 } else {
                     post.likes.push(principal);
+                    events::record(EventKind::PostLiked { post_id, by: principal });
+                    feed::record_interaction(principal, post.author, 1);
                     // Send notification to post author if not self-like
-                    if post.author != caller() {
+                    if post.author != principal {
                         let _ = add_notification_internal(
-                            caller(),
+                            principal,
                             post.author,
                             NotificationType::Like,
                             "liked your post".to_string(),
+                            NotificationTarget::PostId(post_id),
                         );
                     }
                 }
+                sync::touch(Change::Post { post_id, author: post.author });
                 Ok(post.clone())
             }
             None => Err("Post not found".to_string()),
@@ -394,9 +305,9 @@ pub fn like_post(post_id: u64) -> Result<Post, String> {
 
 #[ic_cdk::update]
 pub fn comment_post(post_id: u64, content: String) -> Result<Comment, String> {
-    let principal = caller();
+    let principal = auth::registered_caller()?;
 
-    if content.trim().is_empty() { return Err("Comment cannot be empty".to_string()); }
+    let content = validation::clean_required_text(&content, &validation::COMMENT_CONTENT)?;
 
     let comment_id = get_next_comment_id();
     let comment = Comment { comment_id, author: principal, content, created_at: time() };
@@ -406,12 +317,16 @@ pub fn comment_post(post_id: u64, content: String) -> Result<Comment, String> {
         match posts.get_mut(&post_id) {
             Some(post) => {
                 post.comments.push(comment.clone());
+                feed::record_interaction(principal, post.author, 1);
+                events::record(EventKind::CommentAdded { post_id, comment_id, author: principal });
+                sync::touch(Change::Post { post_id, author: post.author });
                 if post.author != principal {
                     let _ = add_notification_internal(
                         principal,
                         post.author,
                         NotificationType::Comment,
                         "commented on your post".to_string(),
+                        NotificationTarget::CommentId { post_id, comment_id },
                     );
                 }
                 Ok(comment)
@@ -423,7 +338,7 @@ pub fn comment_post(post_id: u64, content: String) -> Result<Comment, String> {
 
 #[ic_cdk::update]
 pub fn repost_post(post_id: u64) -> Result<Post, String> {
-    let principal = caller();
+    let principal = auth::registered_caller()?;
 
     let original_post = POSTS.with(|posts| posts.borrow().get(&post_id).cloned())
         .ok_or("Original post not found")?;
@@ -450,6 +365,10 @@ pub fn repost_post(post_id: u64) -> Result<Post, String> {
     };
 
     POSTS.with(|posts| { posts.borrow_mut().insert(new_post_id, repost.clone()); });
+    sync::touch(Change::Post { post_id: new_post_id, author: principal });
+    timeline::on_post_created(principal, new_post_id);
+    events::record(EventKind::Reposted { post_id: new_post_id, original_post_id: post_id, by: principal });
+    feed::record_interaction(principal, original_post.author, 1);
 
     if original_post.author != principal {
         let _ = add_notification_internal(
@@ -457,6 +376,7 @@ pub fn repost_post(post_id: u64) -> Result<Post, String> {
             original_post.author,
             NotificationType::Repost,
             "reposted your post".to_string(),
+            NotificationTarget::PostId(post_id),
         );
     }
 
@@ -465,7 +385,7 @@ pub fn repost_post(post_id: u64) -> Result<Post, String> {
 
 #[ic_cdk::update]
 pub fn delete_post(post_id: u64) -> Result<String, String> {
-    let principal = caller();
+    let principal = auth::registered_caller()?;
 
     POSTS.with(|posts| {
         let mut posts = posts.borrow_mut();
@@ -473,12 +393,22 @@ pub fn delete_post(post_id: u64) -> Result<String, String> {
             Some(post) => {
                 if post.author != principal { return Err("Unauthorized: Only the author can delete this post".to_string()); }
                 posts.remove(&post_id);
-                let reposts_to_remove: Vec<u64> = posts
+                notifications::on_post_deleted(principal, post_id);
+                sync::touch(Change::Post { post_id, author: principal });
+                events::record(EventKind::PostDeleted { post_id });
+                timeline::on_post_deleted(principal, post_id);
+                let reposts_to_remove: Vec<(u64, Principal)> = posts
                     .iter()
                     .filter(|(_, p)| p.original_post_id == Some(post_id))
-                    .map(|(id, _)| *id)
+                    .map(|(id, p)| (*id, p.author))
                     .collect();
-                for repost_id in reposts_to_remove { posts.remove(&repost_id); }
+                for (repost_id, reposter) in reposts_to_remove {
+                    posts.remove(&repost_id);
+                    notifications::on_post_deleted(reposter, repost_id);
+                    sync::touch(Change::Post { post_id: repost_id, author: reposter });
+                    events::record(EventKind::PostDeleted { post_id: repost_id });
+                    timeline::on_post_deleted(reposter, repost_id);
+                }
                 Ok("Post deleted successfully".to_string())
             }
             None => Err("Post not found".to_string()),
@@ -488,9 +418,13 @@ pub fn delete_post(post_id: u64) -> Result<String, String> {
 
 #[ic_cdk::update]
 pub fn edit_post(post_id: u64, new_content: String, new_image: Option<String>, new_video: Option<String>) -> Result<Post, String> {
-    let principal = caller();
+    let principal = auth::registered_caller()?;
 
-    if new_content.trim().is_empty() && new_image.is_none() && new_video.is_none() {
+    let new_content = validation::clean_text(&new_content, &validation::POST_CONTENT)?;
+    let new_image = validation::clean_optional_media("image", new_image, MediaKind::Image)?;
+    let new_video = validation::clean_optional_media("video", new_video, MediaKind::Video)?;
+
+    if new_content.is_empty() && new_image.is_none() && new_video.is_none() {
         return Err("Post must have content, image, or video".to_string());
     }
 
@@ -502,6 +436,8 @@ pub fn edit_post(post_id: u64, new_content: String, new_image: Option<String>, n
                 post.content = new_content;
                 post.image = new_image;
                 post.video = new_video;
+                sync::touch(Change::Post { post_id, author: principal });
+                events::record(EventKind::PostEdited { post_id });
                 Ok(post.clone())
             }
             None => Err("Post not found".to_string()),
@@ -513,53 +449,138 @@ pub fn edit_post(post_id: u64, new_content: String, new_image: Option<String>, n
 
 #[ic_cdk::update]
 pub fn follow_user(target_principal: Principal) -> Result<String, String> {
-    let principal = caller();
+    let principal = auth::registered_caller()?;
 
     if principal == target_principal { return Err("Cannot follow yourself".to_string()); }
+    if is_blocked_either(principal, target_principal) { return Err("Cannot follow this user".to_string()); }
 
-    USERS.with(|users| {
+    let new_follower = USERS.with(|users| {
         let mut users = users.borrow_mut();
 
         if !users.contains_key(&target_principal) { return Err("Target user not found".to_string()); }
-        if !users.contains_key(&principal) { return Err("Current user not registered".to_string()); }
 
         if let Some(current_user) = users.get_mut(&principal) {
             if !current_user.following.contains(&target_principal) {
-                current_user.following.push(target_principal.clone());
+                current_user.following.push(target_principal);
             }
         }
 
+        let mut new_follower = false;
         if let Some(target_user) = users.get_mut(&target_principal) {
             if !target_user.followers.contains(&principal) {
-                target_user.followers.push(principal.clone());
-                let _ = add_notification_internal(
+                target_user.followers.push(principal);
+                new_follower = true;
+            }
+        }
+        Ok(new_follower)
+    })?;
+    sync::touch(Change::Follow { follower: principal, followee: target_principal });
+    timeline::on_follow(principal, target_principal);
+
+    // outside the USERS borrow: notification preferences read it
+    if new_follower {
+        events::record(EventKind::Followed { follower: principal, followee: target_principal });
+        let _ = add_notification_internal(
+            principal,
+            target_principal,
+            NotificationType::Follow,
+            "started following you".to_string(),
+            NotificationTarget::Profile(principal),
+        );
+    }
+    Ok("Successfully followed user".to_string())
+}
+
+#[ic_cdk::update]
+pub fn unfollow_user(target_principal: Principal) -> Result<String, String> {
+    let principal = auth::registered_caller()?;
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+
+        if let Some(current_user) = users.get_mut(&principal) {
+            current_user.following.retain(|p| *p != target_principal);
+        }
+        if let Some(target_user) = users.get_mut(&target_principal) {
+            if target_user.followers.contains(&principal) {
+                target_user.followers.retain(|p| *p != principal);
+                events::record(EventKind::Unfollowed { follower: principal, followee: target_principal });
+                retract_notification(
                     principal,
                     target_principal,
                     NotificationType::Follow,
-                    "started following you".to_string(),
+                    NotificationTarget::Profile(principal),
                 );
             }
         }
+    });
+    sync::touch(Change::Follow { follower: principal, followee: target_principal });
+    timeline::on_unfollow(principal, target_principal);
 
-        Ok("Successfully followed user".to_string())
-    })
+    Ok("Successfully unfollowed user".to_string())
 }
 
+/// Block a user: removes follows in both directions and declines any pending message request from them
 #[ic_cdk::update]
-pub fn unfollow_user(target_principal: Principal) -> Result<String, String> {
-    let principal = caller();
+pub fn block_user(target_principal: Principal) -> Result<String, String> {
+    let principal = auth::registered_caller()?;
 
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
+    if principal == target_principal { return Err("Cannot block yourself".to_string()); }
 
+    if BLOCKS.with(|blocks| blocks.borrow_mut().insert((principal, target_principal))) {
+        events::record_private(EventKind::Blocked { blocker: principal, blocked: target_principal }, vec![principal]);
+    }
+    // (follower, followee) edges the block removed
+    let removed = USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        let mut removed = Vec::new();
         if let Some(current_user) = users.get_mut(&principal) {
+            if current_user.following.contains(&target_principal) {
+                removed.push((principal, target_principal));
+            }
+            if current_user.followers.contains(&target_principal) {
+                removed.push((target_principal, principal));
+            }
             current_user.following.retain(|p| *p != target_principal);
+            current_user.followers.retain(|p| *p != target_principal);
         }
         if let Some(target_user) = users.get_mut(&target_principal) {
+            target_user.following.retain(|p| *p != principal);
             target_user.followers.retain(|p| *p != principal);
         }
+        removed
+    });
+    for (follower, followee) in removed {
+        events::record(EventKind::Unfollowed { follower, followee });
+    }
+    // whatever was fanned out between them goes, even from old follows
+    timeline::on_unfollow(principal, target_principal);
+    timeline::on_unfollow(target_principal, principal);
+    sync::touch(Change::Follow { follower: principal, followee: target_principal });
+    sync::touch(Change::Follow { follower: target_principal, followee: principal });
+    messaging::on_block(principal, target_principal);
+
+    Ok("User blocked".to_string())
+}
 
-        Ok("Successfully unfollowed user".to_string())
+#[ic_cdk::update]
+pub fn unblock_user(target_principal: Principal) -> Result<String, String> {
+    let principal = auth::registered_caller()?;
+    if BLOCKS.with(|blocks| blocks.borrow_mut().remove(&(principal, target_principal))) {
+        events::record_private(EventKind::Unblocked { blocker: principal, blocked: target_principal }, vec![principal]);
+    }
+    Ok("User unblocked".to_string())
+}
+
+#[ic_cdk::query]
+pub fn get_blocked_users() -> Vec<Principal> {
+    let principal = caller();
+    BLOCKS.with(|blocks| {
+        blocks.borrow()
+            .iter()
+            .filter(|(blocker, _)| *blocker == principal)
+            .map(|(_, blocked)| *blocked)
+            .collect()
     })
 }
 
@@ -594,63 +615,6 @@ pub fn get_following(user_principal: Principal) -> Vec<Principal> {
     })
 }
 
-// Notifications
-
-fn add_notification_internal(
-    sender: Principal,
-    receiver: Principal,
-    notification_type: NotificationType,
-    message: String,
-) -> Result<Notification, String> {
-    let notification_id = get_next_notification_id();
-    let notification = Notification {
-        notification_id,
-        sender,
-        receiver,
-        notification_type,
-        message,
-        created_at: time(),
-        read: false,
-    };
-
-    NOTIFICATIONS.with(|notifications| {
-        notifications.borrow_mut().insert(notification_id, notification.clone());
-    });
-
-    Ok(notification)
-}
-
-#[ic_cdk::query]
-pub fn get_notifications() -> Vec<Notification> {
-    let principal = caller();
-    NOTIFICATIONS.with(|notifications| {
-        let mut user_notifications: Vec<Notification> = notifications
-            .borrow()
-            .values()
-            .filter(|notif| notif.receiver == principal)
-            .cloned()
-            .collect();
-        user_notifications.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-        user_notifications
-    })
-}
-
-#[ic_cdk::update]
-pub fn mark_notification_read(notification_id: u64) -> Result<String, String> {
-    let principal = caller();
-    NOTIFICATIONS.with(|notifications| {
-        let mut notifications = notifications.borrow_mut();
-        match notifications.get_mut(&notification_id) {
-            Some(notification) if notification.receiver == principal => {
-                notification.read = true;
-                Ok("Notification marked as read".to_string())
-            }
-            Some(_) => Err("Unauthorized".to_string()),
-            None => Err("Notification not found".to_string()),
-        }
-    })
-}
-
 // Explore / Feed
 
 #[ic_cdk::query]
@@ -658,51 +622,19 @@ pub fn get_all_users() -> Vec<UserProfile> {
     USERS.with(|users| users.borrow().values().cloned().collect())
 }
 
+/// Users whose name or bio matches every word of `query`; an empty query lists everyone
 #[ic_cdk::query]
 pub fn search_users(query: String) -> Vec<UserProfile> {
-    let query_lower = query.to_lowercase();
+    let tokens = search::tokenize(&query);
     USERS.with(|users| {
         users.borrow()
             .values()
-            .filter(|user| {
-                user.name.to_lowercase().contains(&query_lower) ||
-                user.bio.to_lowercase().contains(&query_lower)
-            })
+            .filter(|user| tokens.is_empty() || search::matches(&tokens, &format!("{} {}", user.name, user.bio)))
             .cloned()
             .collect()
     })
 }
 
-#[ic_cdk::query]
-pub fn get_feed() -> Vec<Post> {
-    let principal = caller();
-
-    let following = USERS.with(|users| {
-        users.borrow()
-            .get(&principal)
-            .map(|user| user.following.clone())
-            .unwrap_or_default()
-    });
-
-    POSTS.with(|posts| {
-        let mut feed_posts: Vec<Post> = posts
-            .borrow()
-            .values()
-            .filter(|post| following.contains(&post.author) || post.author == principal)
-            .cloned()
-            .collect();
-
-        if feed_posts.len() < 10 {
-            let all_posts: Vec<Post> = posts.borrow().values().cloned().collect();
-            feed_posts.extend(all_posts.into_iter().take(20));
-        }
-
-        feed_posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-        feed_posts.dedup_by(|a, b| a.post_id == b.post_id);
-        feed_posts
-    })
-}
-
 // Candid (for dfx generate)
 
 #[ic_cdk::query(name = "__get_candid_interface_tmp_hack")]
@@ -0,0 +1,1870 @@
+// Messenger: direct messages and group conversations.
+//
+// Every chat is a `Conversation` with its own id. A direct conversation is
+// created lazily on the first message between two users and found again
+// through `DM_INDEX`; groups are created explicitly with `create_group`.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, time};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use crate::attachments::{self, Attachment};
+use crate::encryption::{self, EncryptedPayload};
+use crate::events::{self, ConversationChange, EventKind, Setting};
+use crate::notifications::{add_notification_internal, retract_notification, NotificationTarget, NotificationType};
+use crate::sync::{self, Change};
+use crate::{auth, is_blocked_either, search, validation, USERS};
+
+pub const MAX_GROUP_MEMBERS: usize = 50;
+pub const MAX_INBOX_PAGE: u32 = 50;
+pub const MAX_MESSAGE_PAGE: u32 = 100;
+/// Length of the last-message preview shown in the inbox
+pub const PREVIEW_GRAPHEMES: usize = 80;
+/// How long after sending a message can still be edited
+pub const EDIT_WINDOW_NS: u64 = 15 * 60 * 1_000_000_000;
+/// Distinct emoji a single message can collect
+pub const MAX_REACTIONS_PER_MESSAGE: usize = 20;
+pub const MAX_PINNED_CONVERSATIONS: usize = 5;
+pub const MAX_SEARCH_RESULTS: usize = 20;
+/// How often expired disappearing messages are purged
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+/// Upper bound on messages purged per timer tick
+pub const MAX_PURGE_BATCH: usize = 500;
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+
+// Data Structures
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Message {
+    pub id: u64,
+    pub conversation_id: u64,
+    pub from: Principal,
+    /// The peer for direct messages, `None` in groups
+    pub to: Option<Principal>,
+    pub content: String,
+    pub created_at: u64,
+    /// Delivery state as the sender sees it; only direct messages advance past `Sent`
+    pub status: MessageStatus,
+    pub edited_at: Option<u64>,
+    /// Earlier contents, oldest first
+    pub previous_versions: Vec<MessageVersion>,
+    /// Unsent by the author; `content` and history are cleared
+    pub unsent: bool,
+    /// Earlier message in the same conversation this one quotes
+    pub reply_to: Option<u64>,
+    pub reactions: Vec<Reaction>,
+    /// End-to-end encrypted body; `content` is empty when this is set
+    pub encrypted: Option<EncryptedPayload>,
+    /// Set when the conversation had disappearing messages on at send time
+    pub expires_at: Option<u64>,
+    /// Posted by the canister (e.g. a settings change) on behalf of `from`
+    pub system: bool,
+    /// Metadata only; fetch the bytes with `get_attachment`
+    pub attachments: Vec<Attachment>,
+}
+
+/// Only ever moves forward
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageStatus {
+    Sent,
+    /// The recipient's client has fetched it
+    Delivered,
+    /// The recipient has read it, and shares read receipts
+    Read,
+}
+
+/// Disappearing-messages setting of a conversation
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DisappearAfter {
+    #[default]
+    Off,
+    Hours24,
+    Days7,
+    Days90,
+}
+
+impl DisappearAfter {
+    fn duration_ns(self) -> Option<u64> {
+        match self {
+            DisappearAfter::Off => None,
+            DisappearAfter::Hours24 => Some(24 * HOUR_NS),
+            DisappearAfter::Days7 => Some(7 * 24 * HOUR_NS),
+            DisappearAfter::Days90 => Some(90 * 24 * HOUR_NS),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            DisappearAfter::Off => "off",
+            DisappearAfter::Hours24 => "24 hours",
+            DisappearAfter::Days7 => "7 days",
+            DisappearAfter::Days90 => "90 days",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Reaction {
+    pub emoji: String,
+    pub users: Vec<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MessageVersion {
+    pub content: String,
+    /// When this content was replaced
+    pub replaced_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConversationKind {
+    Direct,
+    Group,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemberRole {
+    Admin,
+    Member,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConversationMember {
+    pub user_principal: Principal,
+    pub role: MemberRole,
+    pub joined_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Conversation {
+    pub conversation_id: u64,
+    pub kind: ConversationKind,
+    pub title: Option<String>,
+    pub members: Vec<ConversationMember>,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub last_message_at: u64,
+    pub last_message: Option<MessagePreview>,
+    pub disappear_after: DisappearAfter,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MessagePreview {
+    pub message_id: u64,
+    pub from: Principal,
+    pub text: String,
+    pub created_at: u64,
+    pub unsent: bool,
+    pub encrypted: bool,
+    pub attachment_count: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ProfileSnippet {
+    pub user_principal: Principal,
+    pub name: String,
+    pub profile_image: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InboxItem {
+    pub conversation_id: u64,
+    pub kind: ConversationKind,
+    pub title: Option<String>,
+    /// The other participant of a direct conversation
+    pub peer: Option<ProfileSnippet>,
+    pub member_count: u32,
+    pub last_message: Option<MessagePreview>,
+    pub last_activity: u64,
+    pub unread_count: u64,
+    pub disappear_after: DisappearAfter,
+    pub pinned: bool,
+    pub archived: bool,
+    pub muted_until: Option<u64>,
+}
+
+/// Position in the inbox, as returned in `InboxPage::next_cursor`
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct InboxCursor {
+    pub pinned: bool,
+    pub last_activity: u64,
+    pub conversation_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InboxPage {
+    pub items: Vec<InboxItem>,
+    pub next_cursor: Option<InboxCursor>,
+}
+
+/// Inclusive bounds on `created_at`; either side may be left open
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct DateRange {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MessageSearchHit {
+    pub message_id: u64,
+    pub conversation_id: u64,
+    pub from: Principal,
+    pub created_at: u64,
+    /// The matching part of the message
+    pub snippet: String,
+    /// The messages right before and after it, as the caller sees them
+    pub previous: Option<MessagePreview>,
+    pub next: Option<MessagePreview>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MessageSearchPage {
+    pub hits: Vec<MessageSearchHit>,
+    /// Pass as `cursor` to get older hits
+    pub next_cursor: Option<u64>,
+}
+
+/// Who can start a direct conversation with a user
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DmPolicy {
+    /// Connections go straight to the inbox, everyone else to message requests
+    #[default]
+    Everyone,
+    /// Only people the user follows reach the inbox directly
+    PeopleIFollow,
+    /// Only mutual follows reach the inbox directly
+    MutualsOnly,
+    /// Nobody can start a new conversation
+    Nobody,
+}
+
+/// What happens if the caller messages someone right now
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmAccess {
+    Allowed,
+    /// The message lands in the recipient's message requests
+    Request,
+    Denied,
+}
+
+/// Where a conversation is filed for one member
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+enum Folder {
+    #[default]
+    Inbox,
+    Requests,
+    Archived,
+    /// A declined request; not listed anywhere
+    Declined,
+    /// Deleted by the member; listed again once a new message arrives
+    Deleted,
+}
+
+/// (member, folder, pinned, last_activity, conversation)
+type OrderKey = (Principal, Folder, bool, u64, u64);
+
+/// Per-member state of a conversation
+#[derive(Clone, Debug, Default)]
+struct MemberState {
+    last_read_id: u64,
+    unread_count: u64,
+    last_activity: u64,
+    folder: Folder,
+    pinned: bool,
+    /// No message notifications until then
+    muted_until: Option<u64>,
+    /// Messages up to this id were deleted with `delete_conversation_for_me`
+    cleared_up_to: u64,
+}
+
+impl MemberState {
+    /// Key in `INBOX_ORDER`, or `None` when the conversation is not listed
+    fn order_key(&self, user: Principal, conversation_id: u64) -> Option<OrderKey> {
+        match self.folder {
+            Folder::Declined | Folder::Deleted => None,
+            folder => Some((user, folder, self.pinned, self.last_activity, conversation_id)),
+        }
+    }
+
+    /// What this conversation adds to the app badge
+    fn badge_count(&self) -> u64 {
+        if self.folder == Folder::Inbox { self.unread_count } else { 0 }
+    }
+
+    fn is_muted(&self, now: u64) -> bool {
+        self.muted_until.map(|until| until > now).unwrap_or(false)
+    }
+}
+
+// Storage
+thread_local! {
+    static CONVERSATIONS: RefCell<BTreeMap<u64, Conversation>> = const { RefCell::new(BTreeMap::new()) };
+    static DM_INDEX: RefCell<BTreeMap<(Principal, Principal), u64>> = const { RefCell::new(BTreeMap::new()) };
+    // Messages per conversation, in send order
+    static MESSAGES: RefCell<BTreeMap<u64, Vec<Message>>> = const { RefCell::new(BTreeMap::new()) };
+    // message id -> conversation id
+    static MESSAGE_INDEX: RefCell<BTreeMap<u64, u64>> = const { RefCell::new(BTreeMap::new()) };
+    // (user, message id) pairs deleted "for me"
+    static HIDDEN_MESSAGES: RefCell<BTreeSet<(Principal, u64)>> = const { RefCell::new(BTreeSet::new()) };
+    // Keyed by (member, conversation) so a range over one principal lists their conversations
+    static MEMBER_STATE: RefCell<BTreeMap<(Principal, u64), MemberState>> = const { RefCell::new(BTreeMap::new()) };
+    // Each folder with pinned conversations last, then in recency order; read in reverse
+    static INBOX_ORDER: RefCell<BTreeSet<OrderKey>> = const { RefCell::new(BTreeSet::new()) };
+    static DM_POLICIES: RefCell<BTreeMap<Principal, DmPolicy>> = const { RefCell::new(BTreeMap::new()) };
+    // Users who don't let senders see when they read a message
+    static READ_RECEIPTS_OFF: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    // Sum of unread counts over each user's inbox conversations, for the app badge
+    static UNREAD_TOTALS: RefCell<BTreeMap<Principal, u64>> = const { RefCell::new(BTreeMap::new()) };
+    // (expires_at, message id) for every disappearing message still stored
+    static EXPIRY_QUEUE: RefCell<BTreeSet<(u64, u64)>> = const { RefCell::new(BTreeSet::new()) };
+
+    static CONVERSATION_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static MESSAGE_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// Helpers
+
+fn convo_key(a: Principal, b: Principal) -> (Principal, Principal) {
+    if a.to_text() <= b.to_text() { (a, b) } else { (b, a) }
+}
+
+fn next_message_id() -> u64 {
+    MESSAGE_COUNTER.with(|c| {
+        let mut m = c.borrow_mut();
+        *m += 1;
+        *m
+    })
+}
+
+fn next_conversation_id() -> u64 {
+    CONVERSATION_COUNTER.with(|c| {
+        let mut m = c.borrow_mut();
+        *m += 1;
+        *m
+    })
+}
+
+fn dm_policy(user: Principal) -> DmPolicy {
+    DM_POLICIES.with(|p| p.borrow().get(&user).copied().unwrap_or_default())
+}
+
+/// Effective access for `me` messaging `to`. An existing conversation keeps
+/// whatever state `to` left it in; otherwise `to`'s DM policy decides.
+fn dm_access(me: Principal, to: Principal) -> DmAccess {
+    if me == to || is_blocked_either(me, to) {
+        return DmAccess::Denied;
+    }
+
+    if let Some(id) = dm_conversation_id(me, to) {
+        match MEMBER_STATE.with(|s| s.borrow().get(&(to, id)).map(|state| state.folder)) {
+            Some(Folder::Inbox | Folder::Archived | Folder::Deleted) => return DmAccess::Allowed,
+            Some(Folder::Requests) => return DmAccess::Request,
+            Some(Folder::Declined) => return DmAccess::Denied,
+            None => {}
+        }
+    }
+
+    let (me_follows_to, to_follows_me) = USERS.with(|u| {
+        let u = u.borrow();
+        (
+            u.get(&me).map(|user| user.following.contains(&to)).unwrap_or(false),
+            u.get(&to).map(|user| user.following.contains(&me)).unwrap_or(false),
+        )
+    });
+    let direct = match dm_policy(to) {
+        DmPolicy::Nobody => return DmAccess::Denied,
+        DmPolicy::Everyone => me_follows_to || to_follows_me,
+        DmPolicy::PeopleIFollow => to_follows_me,
+        DmPolicy::MutualsOnly => me_follows_to && to_follows_me,
+    };
+    if direct { DmAccess::Allowed } else { DmAccess::Request }
+}
+
+fn dm_conversation_id(a: Principal, b: Principal) -> Option<u64> {
+    DM_INDEX.with(|d| d.borrow().get(&convo_key(a, b)).copied())
+}
+
+/// Find or open the direct conversation; a new one is filed under the
+/// recipient's message requests when `access` says so
+fn get_or_create_dm(me: Principal, to: Principal, access: DmAccess) -> u64 {
+    if let Some(id) = dm_conversation_id(me, to) {
+        return id;
+    }
+    let now = time();
+    let id = next_conversation_id();
+    let members = [me, to]
+        .into_iter()
+        .map(|p| ConversationMember { user_principal: p, role: MemberRole::Member, joined_at: now })
+        .collect();
+    let conversation = Conversation {
+        conversation_id: id,
+        kind: ConversationKind::Direct,
+        title: None,
+        members,
+        created_by: me,
+        created_at: now,
+        last_message_at: now,
+        last_message: None,
+        disappear_after: DisappearAfter::Off,
+    };
+    CONVERSATIONS.with(|c| c.borrow_mut().insert(id, conversation));
+    DM_INDEX.with(|d| d.borrow_mut().insert(convo_key(me, to), id));
+    add_member_state(me, id, now, Folder::Inbox);
+    let folder = if access == DmAccess::Request { Folder::Requests } else { Folder::Inbox };
+    add_member_state(to, id, now, folder);
+    id
+}
+
+/// Id of the newest message in the conversation, or 0 if it has none
+fn last_message_id(conversation_id: u64) -> u64 {
+    MESSAGES.with(|mm| mm.borrow().get(&conversation_id).and_then(|list| list.last()).map(|m| m.id)).unwrap_or(0)
+}
+
+fn is_member(conversation: &Conversation, user: Principal) -> bool {
+    conversation.members.iter().any(|m| m.user_principal == user)
+}
+
+fn member_ids(conversation_id: u64) -> Vec<Principal> {
+    CONVERSATIONS.with(|c| {
+        c.borrow()
+            .get(&conversation_id)
+            .map(|conversation| conversation.members.iter().map(|m| m.user_principal).collect())
+            .unwrap_or_default()
+    })
+}
+
+fn is_admin(conversation: &Conversation, user: Principal) -> bool {
+    conversation
+        .members
+        .iter()
+        .any(|m| m.user_principal == user && m.role == MemberRole::Admin)
+}
+
+fn get_group_as_member(conversation_id: u64, user: Principal) -> Result<Conversation, String> {
+    let conversation = CONVERSATIONS
+        .with(|c| c.borrow().get(&conversation_id).cloned())
+        .ok_or("Conversation not found")?;
+    if conversation.kind != ConversationKind::Group {
+        return Err("Not a group conversation".to_string());
+    }
+    if !is_member(&conversation, user) {
+        return Err("You are not a member of this group".to_string());
+    }
+    Ok(conversation)
+}
+
+fn save_conversation(conversation: Conversation) {
+    CONVERSATIONS.with(|c| c.borrow_mut().insert(conversation.conversation_id, conversation));
+}
+
+fn preview_of(msg: &Message) -> MessagePreview {
+    MessagePreview {
+        message_id: msg.id,
+        from: msg.from,
+        text: validation::truncate_graphemes(&msg.content, PREVIEW_GRAPHEMES),
+        created_at: msg.created_at,
+        unsent: msg.unsent,
+        encrypted: msg.encrypted.is_some(),
+        attachment_count: msg.attachments.len() as u32,
+    }
+}
+
+/// Message body as sent: plaintext, or an opaque encrypted payload
+enum Body {
+    Plain(String),
+    Encrypted(EncryptedPayload),
+}
+
+impl Body {
+    /// Validate the body for a conversation between `members`.
+    /// Plain text may be empty when the message carries attachments.
+    fn check(self, members: &[Principal], allow_empty: bool) -> Result<Body, String> {
+        match self {
+            Body::Plain(content) if allow_empty => {
+                validation::clean_text(&content, &validation::MESSAGE_CONTENT).map(Body::Plain)
+            }
+            Body::Plain(content) => {
+                validation::clean_required_text(&content, &validation::MESSAGE_CONTENT).map(Body::Plain)
+            }
+            Body::Encrypted(payload) => {
+                encryption::check_payload(&payload, members)?;
+                Ok(Body::Encrypted(payload))
+            }
+        }
+    }
+}
+
+fn new_message(
+    conversation_id: u64,
+    from: Principal,
+    to: Option<Principal>,
+    body: Body,
+    reply_to: Option<u64>,
+) -> Message {
+    let (content, encrypted) = match body {
+        Body::Plain(content) => (content, None),
+        Body::Encrypted(payload) => (String::new(), Some(payload)),
+    };
+    let now = time();
+    let expires_at = CONVERSATIONS
+        .with(|c| c.borrow().get(&conversation_id).map(|conv| conv.disappear_after))
+        .and_then(DisappearAfter::duration_ns)
+        .map(|ttl| now + ttl);
+    Message {
+        id: next_message_id(),
+        conversation_id,
+        from,
+        to,
+        content,
+        created_at: now,
+        status: MessageStatus::Sent,
+        edited_at: None,
+        previous_versions: Vec::new(),
+        unsent: false,
+        reply_to,
+        reactions: Vec::new(),
+        encrypted,
+        expires_at,
+        system: false,
+        attachments: Vec::new(),
+    }
+}
+
+fn conversation_of(message_id: u64) -> Result<u64, String> {
+    MESSAGE_INDEX
+        .with(|i| i.borrow().get(&message_id).copied())
+        .ok_or_else(|| "Message not found".to_string())
+}
+
+pub(crate) fn is_participant(user: Principal, conversation_id: u64) -> bool {
+    MEMBER_STATE.with(|s| s.borrow().contains_key(&(user, conversation_id)))
+}
+
+/// A reply must point at an earlier message of the same conversation
+/// `reply_to` must be a message in the conversation that the sender can still see
+fn check_reply_to(sender: Principal, conversation_id: u64, reply_to: Option<u64>) -> Result<(), String> {
+    match reply_to {
+        Some(id) if conversation_of(id).ok() != Some(conversation_id) || !can_view_message(sender, id) => {
+            Err("Replied-to message is not in this conversation".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn is_hidden(user: Principal, message_id: u64) -> bool {
+    HIDDEN_MESSAGES.with(|h| h.borrow().contains(&(user, message_id)))
+}
+
+/// What one member can see of a conversation right now
+struct Viewer {
+    user: Principal,
+    now: u64,
+    /// History up to this message id was deleted by the member
+    cleared_up_to: u64,
+}
+
+impl Viewer {
+    fn of(user: Principal, conversation_id: u64) -> Viewer {
+        let cleared_up_to = MEMBER_STATE
+            .with(|s| s.borrow().get(&(user, conversation_id)).map(|state| state.cleared_up_to))
+            .unwrap_or(0);
+        Viewer { user, now: time(), cleared_up_to }
+    }
+
+    /// Not deleted by the member and not past its expiry (the purge timer may lag behind)
+    fn sees(&self, msg: &Message) -> bool {
+        msg.id > self.cleared_up_to
+            && !is_hidden(self.user, msg.id)
+            && msg.expires_at.map(|at| at > self.now).unwrap_or(true)
+    }
+}
+
+/// Messages of a conversation as `user` sees them
+fn visible_messages(user: Principal, conversation_id: u64) -> Vec<Message> {
+    let viewer = Viewer::of(user, conversation_id);
+    MESSAGES.with(|mm| {
+        mm.borrow()
+            .get(&conversation_id)
+            .map(|list| list.iter().filter(|m| viewer.sees(m)).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// The message, if `user` takes part in its conversation and can still see it
+pub(crate) fn visible_message(user: Principal, message_id: u64) -> Option<Message> {
+    let conversation_id = conversation_of(message_id).ok()?;
+    if !is_participant(user, conversation_id) {
+        return None;
+    }
+    let viewer = Viewer::of(user, conversation_id);
+    MESSAGES.with(|mm| {
+        mm.borrow()
+            .get(&conversation_id)
+            .and_then(|list| list.binary_search_by_key(&message_id, |m| m.id).ok().map(|pos| &list[pos]))
+            .filter(|m| viewer.sees(m))
+            .cloned()
+    })
+}
+
+pub(crate) fn can_view_message(user: Principal, message_id: u64) -> bool {
+    visible_message(user, message_id).is_some()
+}
+
+/// Run `f` on a stored message, returning the updated copy
+fn update_message(message_id: u64, f: impl FnOnce(&mut Message) -> Result<(), String>) -> Result<Message, String> {
+    let conversation_id = conversation_of(message_id)?;
+    let updated = MESSAGES.with(|mm| {
+        let mut mm = mm.borrow_mut();
+        let list = mm.get_mut(&conversation_id).ok_or("Message not found")?;
+        let pos = list
+            .binary_search_by_key(&message_id, |m| m.id)
+            .map_err(|_| "Message not found".to_string())?;
+        f(&mut list[pos])?;
+        Ok::<Message, String>(list[pos].clone())
+    })?;
+    sync::touch(Change::Message { message_id, conversation_id });
+
+    // keep the inbox preview in step when the last message changes
+    CONVERSATIONS.with(|c| {
+        if let Some(conversation) = c.borrow_mut().get_mut(&conversation_id) {
+            if conversation.last_message.as_ref().map(|p| p.message_id) == Some(message_id) {
+                conversation.last_message = Some(preview_of(&updated));
+            }
+        }
+    });
+    Ok(updated)
+}
+
+/// Latest preview `user` can see, skipping messages they deleted for themselves
+/// and expired ones the purge has not reached yet
+fn preview_for(user: Principal, conversation: &Conversation) -> Option<MessagePreview> {
+    conversation.last_message.as_ref()?;
+    let viewer = Viewer::of(user, conversation.conversation_id);
+    MESSAGES.with(|mm| {
+        mm.borrow()
+            .get(&conversation.conversation_id)?
+            .iter()
+            .rev()
+            .find(|m| viewer.sees(m))
+            .map(preview_of)
+    })
+}
+
+/// Store a message, refresh the preview and bump every member's inbox entry
+fn append_message(msg: &Message) {
+    let members: Vec<Principal> = CONVERSATIONS.with(|c| {
+        let mut c = c.borrow_mut();
+        match c.get_mut(&msg.conversation_id) {
+            Some(conversation) => {
+                conversation.last_message_at = msg.created_at;
+                conversation.last_message = Some(preview_of(msg));
+                conversation.members.iter().map(|m| m.user_principal).collect()
+            }
+            None => Vec::new(),
+        }
+    });
+
+    MESSAGES.with(|mm| mm.borrow_mut().entry(msg.conversation_id).or_default().push(msg.clone()));
+    MESSAGE_INDEX.with(|i| i.borrow_mut().insert(msg.id, msg.conversation_id));
+    sync::touch(Change::Message { message_id: msg.id, conversation_id: msg.conversation_id });
+    events::record_private(
+        EventKind::MessageSent { message_id: msg.id, conversation_id: msg.conversation_id, from: msg.from },
+        members.clone(),
+    );
+    if let Some(at) = msg.expires_at {
+        EXPIRY_QUEUE.with(|q| q.borrow_mut().insert((at, msg.id)));
+    }
+
+    for member in members {
+        update_member_state(member, msg.conversation_id, |state| {
+            state.last_activity = msg.created_at;
+            if member == msg.from {
+                // sending implies having read everything before it
+                state.last_read_id = msg.id;
+                state.unread_count = 0;
+            } else {
+                state.unread_count += 1;
+            }
+            // new activity brings back a deleted conversation, and an archived
+            // one unless the member also muted it
+            let unarchive = state.folder == Folder::Archived && (member == msg.from || !state.is_muted(msg.created_at));
+            if state.folder == Folder::Deleted || unarchive {
+                state.folder = Folder::Inbox;
+            }
+        });
+    }
+}
+
+/// Advance direct messages from the other side up to `last_id` to `status`,
+/// returning the ids that changed
+fn advance_status(me: Principal, conversation_id: u64, last_id: u64, status: MessageStatus) -> Vec<u64> {
+    let mut changed = Vec::new();
+    MESSAGES.with(|mm| {
+        if let Some(list) = mm.borrow_mut().get_mut(&conversation_id) {
+            let end = list.partition_point(|m| m.id <= last_id);
+            for m in list[..end].iter_mut() {
+                if m.from != me && !m.system && m.status < status {
+                    m.status = status;
+                    changed.push(m.id);
+                }
+            }
+        }
+    });
+    for message_id in &changed {
+        sync::touch(Change::Message { message_id: *message_id, conversation_id });
+    }
+    changed
+}
+
+/// Reading a direct conversation: messages become `Read`, or only
+/// `Delivered` when the reader has read receipts turned off
+fn set_seen(me: Principal, conversation_id: u64, last_id: u64) -> Vec<u64> {
+    let status = if read_receipts_enabled(me) { MessageStatus::Read } else { MessageStatus::Delivered };
+    advance_status(me, conversation_id, last_id, status)
+}
+
+fn read_receipts_enabled(user: Principal) -> bool {
+    !READ_RECEIPTS_OFF.with(|r| r.borrow().contains(&user))
+}
+
+/// Move the caller's read cursor forward and recount what is still unread
+fn mark_read(me: Principal, conversation_id: u64, last_id: u64) {
+    let unread = MESSAGES.with(|mm| {
+        mm.borrow()
+            .get(&conversation_id)
+            .map(|list| {
+                list.iter()
+                    .rev()
+                    .take_while(|m| m.id > last_id)
+                    .filter(|m| m.from != me)
+                    .count() as u64
+            })
+            .unwrap_or(0)
+    });
+    update_member_state(me, conversation_id, |state| {
+        if last_id > state.last_read_id {
+            state.last_read_id = last_id;
+            state.unread_count = unread;
+        }
+    });
+}
+
+fn reindex(before: Option<OrderKey>, after: Option<OrderKey>) {
+    if before == after {
+        return;
+    }
+    INBOX_ORDER.with(|o| {
+        let mut o = o.borrow_mut();
+        if let Some(key) = before { o.remove(&key); }
+        if let Some(key) = after { o.insert(key); }
+    });
+}
+
+/// Swap `before` for `after` in a user's unread total
+fn adjust_unread_total(user: Principal, before: u64, after: u64) {
+    if before == after {
+        return;
+    }
+    UNREAD_TOTALS.with(|t| {
+        let mut t = t.borrow_mut();
+        let total = t.entry(user).or_default();
+        *total = (*total + after).saturating_sub(before);
+        if *total == 0 { t.remove(&user); }
+    });
+}
+
+/// Apply `f` to an existing member state, keeping `INBOX_ORDER` and the
+/// unread total in step
+fn update_member_state(user: Principal, conversation_id: u64, f: impl FnOnce(&mut MemberState)) {
+    MEMBER_STATE.with(|s| {
+        if let Some(state) = s.borrow_mut().get_mut(&(user, conversation_id)) {
+            let before = (state.order_key(user, conversation_id), state.badge_count());
+            f(state);
+            reindex(before.0, state.order_key(user, conversation_id));
+            adjust_unread_total(user, before.1, state.badge_count());
+        }
+    });
+}
+
+fn add_member_state(user: Principal, conversation_id: u64, activity: u64, folder: Folder) {
+    let state = MemberState { last_activity: activity, folder, ..Default::default() };
+    reindex(None, state.order_key(user, conversation_id));
+    MEMBER_STATE.with(|s| s.borrow_mut().insert((user, conversation_id), state));
+}
+
+/// Forget a member's state in a conversation, including messages they deleted for themselves
+fn remove_member_state(user: Principal, conversation_id: u64) {
+    if let Some(state) = MEMBER_STATE.with(|s| s.borrow_mut().remove(&(user, conversation_id))) {
+        reindex(state.order_key(user, conversation_id), None);
+        adjust_unread_total(user, state.badge_count(), 0);
+    }
+    HIDDEN_MESSAGES.with(|h| {
+        let mut h = h.borrow_mut();
+        let theirs: Vec<(Principal, u64)> = h
+            .range((user, 0)..=(user, u64::MAX))
+            .filter(|(_, id)| conversation_of(*id).ok() == Some(conversation_id))
+            .copied()
+            .collect();
+        for key in theirs {
+            h.remove(&key);
+        }
+    });
+}
+
+fn folder_of(user: Principal, conversation_id: u64) -> Option<Folder> {
+    MEMBER_STATE.with(|s| s.borrow().get(&(user, conversation_id)).map(|state| state.folder))
+}
+
+/// One page of a folder, pinned conversations first, then most recently active
+fn folder_page(me: Principal, folder: Folder, cursor: Option<InboxCursor>, limit: u32) -> InboxPage {
+    let limit = limit.clamp(1, MAX_INBOX_PAGE) as usize;
+    let upper = match cursor {
+        Some(c) => (me, folder, c.pinned, c.last_activity, c.conversation_id),
+        None => (me, folder, true, u64::MAX, u64::MAX),
+    };
+
+    // one extra entry tells us whether there is another page
+    let keys: Vec<OrderKey> = INBOX_ORDER.with(|o| {
+        o.borrow()
+            .range((me, folder, false, 0, 0)..upper)
+            .rev()
+            .take(limit + 1)
+            .copied()
+            .collect()
+    });
+
+    let has_more = keys.len() > limit;
+    let items: Vec<InboxItem> = keys.iter().take(limit).filter_map(|key| inbox_item(me, key.4)).collect();
+    let next_cursor = if has_more {
+        keys.get(limit - 1).map(|(_, _, pinned, activity, id)| InboxCursor {
+            pinned: *pinned,
+            last_activity: *activity,
+            conversation_id: *id,
+        })
+    } else {
+        None
+    };
+    InboxPage { items, next_cursor }
+}
+
+/// Called when `blocker` blocks `blocked`: a pending request from them is declined
+pub(crate) fn on_block(blocker: Principal, blocked: Principal) {
+    if let Some(id) = dm_conversation_id(blocker, blocked) {
+        update_member_state(blocker, id, |state| {
+            if state.folder == Folder::Requests { state.folder = Folder::Declined; }
+        });
+    }
+}
+
+fn profile_snippet(user: Principal) -> ProfileSnippet {
+    USERS.with(|u| match u.borrow().get(&user) {
+        Some(profile) => ProfileSnippet {
+            user_principal: user,
+            name: profile.name.clone(),
+            profile_image: profile.profile_image.clone(),
+        },
+        None => ProfileSnippet { user_principal: user, name: String::new(), profile_image: String::new() },
+    })
+}
+
+fn inbox_item(me: Principal, conversation_id: u64) -> Option<InboxItem> {
+    let state = MEMBER_STATE.with(|s| s.borrow().get(&(me, conversation_id)).cloned())?;
+    CONVERSATIONS.with(|c| {
+        let c = c.borrow();
+        let conversation = c.get(&conversation_id)?;
+        let peer = match conversation.kind {
+            ConversationKind::Direct => conversation
+                .members
+                .iter()
+                .map(|m| m.user_principal)
+                .find(|p| *p != me)
+                .map(profile_snippet),
+            ConversationKind::Group => None,
+        };
+        Some(InboxItem {
+            conversation_id,
+            kind: conversation.kind,
+            title: conversation.title.clone(),
+            peer,
+            member_count: conversation.members.len() as u32,
+            last_message: preview_for(me, conversation),
+            last_activity: state.last_activity,
+            unread_count: state.unread_count,
+            disappear_after: conversation.disappear_after,
+            pinned: state.pinned,
+            archived: state.folder == Folder::Archived,
+            muted_until: state.muted_until.filter(|until| *until > time()),
+        })
+    })
+}
+
+/// Validate users being added to a group by `adder`
+fn check_new_members(adder: Principal, users: &[Principal]) -> Result<(), String> {
+    for user in users {
+        if !USERS.with(|u| u.borrow().contains_key(user)) {
+            return Err(format!("User {} not found", user));
+        }
+        if dm_access(adder, *user) != DmAccess::Allowed {
+            return Err(format!("You can only add users who accept your messages ({})", user));
+        }
+    }
+    Ok(())
+}
+
+// Direct messages
+
+fn send_direct(to: Principal, body: Body, attachment_ids: &[u64], reply_to: Option<u64>) -> Result<Message, String> {
+    let me = auth::registered_caller()?;
+    let body = body.check(&[me, to], !attachment_ids.is_empty())?;
+    attachments::check_pending(me, attachment_ids)?;
+
+    if me == to { return Err("Cannot message yourself".into()); }
+    if !USERS.with(|u| u.borrow().contains_key(&to)) { return Err("Receiver not found".into()); }
+
+    let access = dm_access(me, to);
+    if access == DmAccess::Denied {
+        return Err("This user is not accepting messages from you".into());
+    }
+
+    // a reply can only point into an existing conversation; check it before
+    // anything is created so a bad one leaves no empty thread behind
+    if reply_to.is_some() {
+        let existing = dm_conversation_id(me, to).ok_or("Replied-to message is not in this conversation")?;
+        check_reply_to(me, existing, reply_to)?;
+    }
+
+    let conversation_id = get_or_create_dm(me, to, access);
+    // replying to a request accepts it
+    update_member_state(me, conversation_id, |state| state.folder = Folder::Inbox);
+    let mut msg = new_message(conversation_id, me, Some(to), body, reply_to);
+    msg.attachments = attachments::claim(attachment_ids, msg.id);
+    append_message(&msg);
+
+    // notify receiver, unless they muted the conversation
+    let muted = MEMBER_STATE.with(|s| {
+        s.borrow().get(&(to, conversation_id)).map(|state| state.is_muted(msg.created_at)).unwrap_or(false)
+    });
+    if !muted {
+        let text = if access == DmAccess::Request { "sent you a message request" } else { "sent you a message" };
+        let target = NotificationTarget::MessageId { message_id: msg.id, conversation_id, peer: me };
+        let _ = add_notification_internal(me, to, NotificationType::Message, text.to_string(), target);
+    }
+
+    Ok(msg)
+}
+
+/// Send a message. Depending on the recipient's DM policy it lands in their
+/// inbox or in their message requests.
+#[ic_cdk::update]
+pub fn send_message(to: Principal, content: String, reply_to: Option<u64>) -> Result<Message, String> {
+    send_direct(to, Body::Plain(content), &[], reply_to)
+}
+
+/// Send files uploaded with `upload_attachment`, with an optional caption
+#[ic_cdk::update]
+pub fn send_attachment_message(
+    to: Principal,
+    caption: String,
+    attachment_ids: Vec<u64>,
+    reply_to: Option<u64>,
+) -> Result<Message, String> {
+    if attachment_ids.is_empty() {
+        return Err("No attachments given".to_string());
+    }
+    send_direct(to, Body::Plain(caption), &attachment_ids, reply_to)
+}
+
+/// Like `send_message`, with a client-encrypted body wrapped for both participants
+#[ic_cdk::update]
+pub fn send_encrypted_message(to: Principal, payload: EncryptedPayload, reply_to: Option<u64>) -> Result<Message, String> {
+    send_direct(to, Body::Encrypted(payload), &[], reply_to)
+}
+
+/// Handy: what happens if the current caller messages `to`?
+#[ic_cdk::query]
+pub fn can_message(to: Principal) -> DmAccess {
+    dm_access(caller(), to)
+}
+
+#[ic_cdk::update]
+pub fn set_dm_policy(policy: DmPolicy) -> Result<DmPolicy, String> {
+    let me = auth::registered_caller()?;
+    DM_POLICIES.with(|p| p.borrow_mut().insert(me, policy));
+    events::record_for(me, EventKind::SettingChanged { user: me, setting: Setting::DmPolicy });
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+pub fn get_dm_policy() -> DmPolicy {
+    dm_policy(caller())
+}
+
+/// Get full conversation with someone (in send order)
+#[ic_cdk::query]
+pub fn get_conversation(with_user: Principal) -> Vec<Message> {
+    let me = caller();
+    match dm_conversation_id(me, with_user) {
+        Some(id) => visible_messages(me, id),
+        None => Vec::new(),
+    }
+}
+
+/// Up to `limit` messages `me` can see right before `before_id` (or the latest
+/// ones), oldest first. Messages are stored in send order, so this is a binary
+/// search and a slice.
+fn messages_before(me: Principal, id: u64, before_id: Option<u64>, limit: u32) -> Vec<Message> {
+    let limit = limit.clamp(1, MAX_MESSAGE_PAGE) as usize;
+    let viewer = Viewer::of(me, id);
+    MESSAGES.with(|mm| {
+        let mm = mm.borrow();
+        let Some(list) = mm.get(&id) else { return Vec::new() };
+        let end = match before_id {
+            Some(before) => list.partition_point(|m| m.id < before),
+            None => list.len(),
+        };
+        let mut page: Vec<Message> = list[..end]
+            .iter()
+            .rev()
+            .filter(|m| viewer.sees(m))
+            .take(limit)
+            .cloned()
+            .collect();
+        page.reverse();
+        page
+    })
+}
+
+/// Messages `me` can see newer than `after_id`, oldest first, at most `MAX_MESSAGE_PAGE`
+fn messages_since(me: Principal, id: u64, after_id: u64) -> Vec<Message> {
+    let viewer = Viewer::of(me, id);
+    MESSAGES.with(|mm| {
+        let mm = mm.borrow();
+        let Some(list) = mm.get(&id) else { return Vec::new() };
+        let start = list.partition_point(|m| m.id <= after_id);
+        list[start..]
+            .iter()
+            .filter(|m| viewer.sees(m))
+            .take(MAX_MESSAGE_PAGE as usize)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Up to `limit` messages right before `before_id` (or the latest ones), oldest first
+#[ic_cdk::query]
+pub fn get_messages(peer: Principal, before_id: Option<u64>, limit: u32) -> Vec<Message> {
+    let me = caller();
+    let Some(id) = dm_conversation_id(me, peer) else { return Vec::new() };
+    messages_before(me, id, before_id, limit)
+}
+
+/// Messages newer than `after_id`, oldest first, at most `MAX_MESSAGE_PAGE` per call.
+/// A full page means there may be more; call again with the last id.
+#[ic_cdk::query]
+pub fn get_messages_since(peer: Principal, after_id: u64) -> Vec<Message> {
+    let me = caller();
+    let Some(id) = dm_conversation_id(me, peer) else { return Vec::new() };
+    messages_since(me, id, after_id)
+}
+
+/// Mark as seen (all messages FROM `with_user` TO me up to last_id).
+/// Returns the ids whose status changed.
+#[ic_cdk::update]
+pub fn mark_seen(with_user: Principal, last_id: u64) -> Result<Vec<u64>, String> {
+    let me = auth::registered_caller()?;
+    let id = dm_conversation_id(me, with_user).ok_or("No conversation")?;
+    let changed = set_seen(me, id, last_id);
+    mark_read(me, id, last_id);
+    events::record_for(me, EventKind::ConversationRead { conversation_id: id, by: me, up_to: last_id });
+    Ok(changed)
+}
+
+/// Acknowledge that messages from `with_user` up to `last_id` reached this
+/// device, without reading them. Returns the ids whose status changed.
+#[ic_cdk::update]
+pub fn mark_delivered(with_user: Principal, last_id: u64) -> Result<Vec<u64>, String> {
+    let me = auth::registered_caller()?;
+    let id = dm_conversation_id(me, with_user).ok_or("No conversation")?;
+    let changed = advance_status(me, id, last_id, MessageStatus::Delivered);
+    events::record_for(me, EventKind::MessagesDelivered { conversation_id: id, by: me, up_to: last_id });
+    Ok(changed)
+}
+
+pub(crate) fn unread_total(user: Principal) -> u64 {
+    UNREAD_TOTALS.with(|t| t.borrow().get(&user).copied().unwrap_or(0))
+}
+
+/// Unread messages across the caller's inbox conversations
+#[ic_cdk::query]
+pub fn get_unread_total() -> u64 {
+    unread_total(caller())
+}
+
+/// With read receipts off, senders see the caller's messages as delivered, never read
+#[ic_cdk::update]
+pub fn set_read_receipts(enabled: bool) -> Result<bool, String> {
+    let me = auth::registered_caller()?;
+    READ_RECEIPTS_OFF.with(|r| {
+        let mut r = r.borrow_mut();
+        if enabled { r.remove(&me); } else { r.insert(me); }
+    });
+    events::record_for(me, EventKind::SettingChanged { user: me, setting: Setting::ReadReceipts });
+    Ok(enabled)
+}
+
+#[ic_cdk::query]
+pub fn get_read_receipts() -> bool {
+    read_receipts_enabled(caller())
+}
+
+/// Mark any conversation the caller belongs to as read up to `last_id`
+#[ic_cdk::update]
+pub fn mark_conversation_read(conversation_id: u64, last_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    let conversation = CONVERSATIONS
+        .with(|c| c.borrow().get(&conversation_id).cloned())
+        .ok_or("Conversation not found")?;
+    if !is_member(&conversation, me) {
+        return Err("You are not a member of this conversation".into());
+    }
+    if conversation.kind == ConversationKind::Direct {
+        set_seen(me, conversation_id, last_id);
+    }
+    mark_read(me, conversation_id, last_id);
+    events::record_for(me, EventKind::ConversationRead { conversation_id, by: me, up_to: last_id });
+    Ok("read updated".into())
+}
+
+/// The caller's conversations, most recently active first.
+/// Pass the previous page's `next_cursor` to continue.
+#[ic_cdk::query]
+pub fn get_inbox(cursor: Option<InboxCursor>, limit: u32) -> InboxPage {
+    folder_page(caller(), Folder::Inbox, cursor, limit)
+}
+
+// Message requests
+
+/// First messages from people who don't meet the caller's DM policy
+#[ic_cdk::query]
+pub fn get_message_requests(cursor: Option<InboxCursor>, limit: u32) -> InboxPage {
+    folder_page(caller(), Folder::Requests, cursor, limit)
+}
+
+fn pending_request(me: Principal, from: Principal) -> Result<u64, String> {
+    let id = dm_conversation_id(me, from).ok_or("No message request from this user")?;
+    if folder_of(me, id) != Some(Folder::Requests) {
+        return Err("No message request from this user".to_string());
+    }
+    Ok(id)
+}
+
+/// Move a request into the inbox; the sender can message freely from now on
+#[ic_cdk::update]
+pub fn accept_message_request(from: Principal) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    let id = pending_request(me, from)?;
+    update_member_state(me, id, |state| state.folder = Folder::Inbox);
+    events::record_private(EventKind::MessageRequestAccepted { conversation_id: id, by: me, from }, vec![me, from]);
+    Ok("Message request accepted".to_string())
+}
+
+/// Drop a request; the sender is not told but cannot send further messages
+#[ic_cdk::update]
+pub fn decline_message_request(from: Principal) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    let id = pending_request(me, from)?;
+    update_member_state(me, id, |state| state.folder = Folder::Declined);
+    // only the decliner: the sender is not told
+    events::record_private(EventKind::MessageRequestDeclined { conversation_id: id, by: me, from }, vec![me]);
+    Ok("Message request declined".to_string())
+}
+
+// Search
+
+/// Search the caller's conversations (or only the one with `peer`), newest
+/// hits first. Encrypted messages can't be searched here.
+#[ic_cdk::query]
+pub fn search_messages(
+    query: String,
+    peer: Option<Principal>,
+    cursor: Option<u64>,
+    range: Option<DateRange>,
+) -> Result<MessageSearchPage, String> {
+    let me = auth::registered_caller()?;
+    let tokens = search::tokenize(&query);
+    if tokens.is_empty() {
+        return Err("Search query cannot be empty".to_string());
+    }
+    let conversation_ids: Vec<u64> = match peer {
+        Some(peer) => dm_conversation_id(me, peer).into_iter().collect(),
+        None => MEMBER_STATE.with(|s| {
+            s.borrow()
+                .range((me, 0)..=(me, u64::MAX))
+                .map(|((_, id), _)| *id)
+                .collect()
+        }),
+    };
+    let before = cursor.unwrap_or(u64::MAX);
+    let from = range.and_then(|r| r.from).unwrap_or(0);
+    let to = range.and_then(|r| r.to).unwrap_or(u64::MAX);
+
+    let mut hits: Vec<MessageSearchHit> = Vec::new();
+    MESSAGES.with(|mm| {
+        let mm = mm.borrow();
+        for id in conversation_ids {
+            let Some(list) = mm.get(&id) else { continue };
+            let viewer = Viewer::of(me, id);
+            let visible: Vec<&Message> = list.iter().filter(|m| viewer.sees(m)).collect();
+            let end = visible.partition_point(|m| m.id < before);
+            // newest first, and no more than a page from each conversation
+            let found = (0..end)
+                .rev()
+                .filter(|i| {
+                    let m = visible[*i];
+                    !m.unsent
+                        && !m.system
+                        && m.created_at >= from
+                        && m.created_at <= to
+                        && search::matches(&tokens, &m.content)
+                })
+                .take(MAX_SEARCH_RESULTS + 1);
+            for i in found {
+                let m = visible[i];
+                hits.push(MessageSearchHit {
+                    message_id: m.id,
+                    conversation_id: id,
+                    from: m.from,
+                    created_at: m.created_at,
+                    snippet: search::snippet(&m.content, &tokens),
+                    previous: i.checked_sub(1).map(|p| preview_of(visible[p])),
+                    next: visible.get(i + 1).map(|n| preview_of(n)),
+                });
+            }
+        }
+    });
+
+    // message ids grow over time, so they order hits across conversations
+    hits.sort_by_key(|h| std::cmp::Reverse(h.message_id));
+    let next_cursor = if hits.len() > MAX_SEARCH_RESULTS {
+        hits.truncate(MAX_SEARCH_RESULTS);
+        hits.last().map(|h| h.message_id)
+    } else {
+        None
+    };
+    Ok(MessageSearchPage { hits, next_cursor })
+}
+
+// Archive, mute, pin and delete
+
+/// The caller's state of a conversation they take part in
+fn own_state(me: Principal, conversation_id: u64) -> Result<MemberState, String> {
+    MEMBER_STATE
+        .with(|s| s.borrow().get(&(me, conversation_id)).cloned())
+        .ok_or_else(|| "Conversation not found".to_string())
+}
+
+/// Archived conversations, most recently active first
+#[ic_cdk::query]
+pub fn get_archived_conversations(cursor: Option<InboxCursor>, limit: u32) -> InboxPage {
+    folder_page(caller(), Folder::Archived, cursor, limit)
+}
+
+fn filed(me: Principal, conversation_id: u64, change: ConversationChange) {
+    events::record_for(me, EventKind::ConversationFiled { conversation_id, by: me, change });
+}
+
+/// Move a conversation out of the inbox. It comes back when a new message
+/// arrives, unless it is also muted.
+#[ic_cdk::update]
+pub fn archive_conversation(conversation_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    if own_state(me, conversation_id)?.folder != Folder::Inbox {
+        return Err("Only inbox conversations can be archived".to_string());
+    }
+    update_member_state(me, conversation_id, |state| {
+        state.folder = Folder::Archived;
+        state.pinned = false;
+    });
+    filed(me, conversation_id, ConversationChange::Archived);
+    Ok("Conversation archived".to_string())
+}
+
+#[ic_cdk::update]
+pub fn unarchive_conversation(conversation_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    if own_state(me, conversation_id)?.folder != Folder::Archived {
+        return Err("Conversation is not archived".to_string());
+    }
+    update_member_state(me, conversation_id, |state| state.folder = Folder::Inbox);
+    filed(me, conversation_id, ConversationChange::Unarchived);
+    Ok("Conversation unarchived".to_string())
+}
+
+/// Silence message notifications until `until` (nanoseconds), or until unmuted
+#[ic_cdk::update]
+pub fn mute_conversation(conversation_id: u64, until: Option<u64>) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    own_state(me, conversation_id)?;
+    let until = until.unwrap_or(u64::MAX);
+    if until <= time() {
+        return Err("Mute end must be in the future".to_string());
+    }
+    update_member_state(me, conversation_id, |state| state.muted_until = Some(until));
+    filed(me, conversation_id, ConversationChange::Muted);
+    Ok("Conversation muted".to_string())
+}
+
+#[ic_cdk::update]
+pub fn unmute_conversation(conversation_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    own_state(me, conversation_id)?;
+    update_member_state(me, conversation_id, |state| state.muted_until = None);
+    filed(me, conversation_id, ConversationChange::Unmuted);
+    Ok("Conversation unmuted".to_string())
+}
+
+/// Keep a conversation at the top of the inbox
+#[ic_cdk::update]
+pub fn pin_conversation(conversation_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    let state = own_state(me, conversation_id)?;
+    if state.folder != Folder::Inbox {
+        return Err("Only inbox conversations can be pinned".to_string());
+    }
+    if state.pinned {
+        return Ok("Conversation pinned".to_string());
+    }
+    let pinned = INBOX_ORDER.with(|o| {
+        o.borrow()
+            .range((me, Folder::Inbox, true, 0, 0)..=(me, Folder::Inbox, true, u64::MAX, u64::MAX))
+            .count()
+    });
+    if pinned >= MAX_PINNED_CONVERSATIONS {
+        return Err(format!("You can pin at most {} conversations", MAX_PINNED_CONVERSATIONS));
+    }
+    update_member_state(me, conversation_id, |state| state.pinned = true);
+    filed(me, conversation_id, ConversationChange::Pinned);
+    Ok("Conversation pinned".to_string())
+}
+
+#[ic_cdk::update]
+pub fn unpin_conversation(conversation_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    own_state(me, conversation_id)?;
+    update_member_state(me, conversation_id, |state| state.pinned = false);
+    filed(me, conversation_id, ConversationChange::Unpinned);
+    Ok("Conversation unpinned".to_string())
+}
+
+/// Delete the conversation's history up to now for the caller only. The
+/// conversation leaves every list until someone sends a new message.
+#[ic_cdk::update]
+pub fn delete_conversation_for_me(conversation_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    if own_state(me, conversation_id)?.folder == Folder::Requests {
+        return Err("Decline the message request instead".to_string());
+    }
+    let last_id = last_message_id(conversation_id);
+    update_member_state(me, conversation_id, |state| {
+        state.cleared_up_to = state.cleared_up_to.max(last_id);
+        state.last_read_id = state.last_read_id.max(last_id);
+        state.unread_count = 0;
+        state.pinned = false;
+        if state.folder != Folder::Declined {
+            state.folder = Folder::Deleted;
+        }
+    });
+    filed(me, conversation_id, ConversationChange::Deleted);
+    Ok("Conversation deleted".to_string())
+}
+
+// Editing and deleting messages
+
+/// Edit your own message within `EDIT_WINDOW_NS` of sending it
+#[ic_cdk::update]
+pub fn edit_message(message_id: u64, content: String) -> Result<Message, String> {
+    let me = auth::registered_caller()?;
+    let content = validation::clean_required_text(&content, &validation::MESSAGE_CONTENT)?;
+    let now = time();
+
+    let mut changed = false;
+    let msg = update_message(message_id, |m| {
+        if m.from != me || m.system { return Err("Unauthorized: Only the sender can edit this message".to_string()); }
+        if m.unsent { return Err("Message was unsent".to_string()); }
+        if m.encrypted.is_some() { return Err("Encrypted messages cannot be edited".to_string()); }
+        if now.saturating_sub(m.created_at) > EDIT_WINDOW_NS {
+            return Err("Messages can only be edited within 15 minutes of sending".to_string());
+        }
+        if m.content == content { return Ok(()); }
+        let previous = std::mem::replace(&mut m.content, content);
+        m.previous_versions.push(MessageVersion { content: previous, replaced_at: now });
+        m.edited_at = Some(now);
+        changed = true;
+        Ok(())
+    })?;
+    if changed {
+        let kind = EventKind::MessageEdited { message_id, conversation_id: msg.conversation_id };
+        events::record_private(kind, member_ids(msg.conversation_id));
+    }
+    Ok(msg)
+}
+
+/// Unsend your own message: both sides keep a tombstone in its place
+#[ic_cdk::update]
+pub fn unsend_message(message_id: u64) -> Result<Message, String> {
+    let me = auth::registered_caller()?;
+    let mut dropped = Vec::new();
+    let msg = update_message(message_id, |m| {
+        if m.from != me || m.system { return Err("Unauthorized: Only the sender can unsend this message".to_string()); }
+        m.unsent = true;
+        m.content.clear();
+        m.previous_versions.clear();
+        m.encrypted = None;
+        dropped = std::mem::take(&mut m.attachments);
+        Ok(())
+    })?;
+    attachments::delete(&dropped);
+    let kind = EventKind::MessageUnsent { message_id, conversation_id: msg.conversation_id };
+    events::record_private(kind, member_ids(msg.conversation_id));
+    Ok(msg)
+}
+
+/// Hide a message from your own view of the conversation only
+#[ic_cdk::update]
+pub fn delete_message_for_me(message_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    let conversation_id = conversation_of(message_id)?;
+    if !is_participant(me, conversation_id) {
+        return Err("Message not found".to_string());
+    }
+    HIDDEN_MESSAGES.with(|h| h.borrow_mut().insert((me, message_id)));
+    sync::touch(Change::Message { message_id, conversation_id });
+    events::record_for(me, EventKind::MessageDeletedForMe { message_id, conversation_id, by: me });
+    Ok("Message deleted".to_string())
+}
+
+/// A single message, e.g. to resolve a `reply_to` outside the loaded page
+#[ic_cdk::query]
+pub fn get_message(message_id: u64) -> Result<Message, String> {
+    let me = caller();
+    let conversation_id = conversation_of(message_id)?;
+    if !is_participant(me, conversation_id) {
+        return Err("Message not found".to_string());
+    }
+    let viewer = Viewer::of(me, conversation_id);
+    MESSAGES.with(|mm| {
+        mm.borrow()
+            .get(&conversation_id)
+            .and_then(|list| list.binary_search_by_key(&message_id, |m| m.id).ok().map(|pos| &list[pos]))
+            .filter(|m| viewer.sees(m))
+            .cloned()
+            .ok_or_else(|| "Message not found".to_string())
+    })
+}
+
+// Reactions
+
+/// React with an emoji; each user can use each emoji once per message
+#[ic_cdk::update]
+pub fn react_to_message(message_id: u64, emoji: String) -> Result<Message, String> {
+    let me = auth::registered_caller()?;
+    let emoji = validation::clean_emoji(&emoji)?;
+    if !can_view_message(me, message_id) {
+        return Err("Message not found".to_string());
+    }
+
+    let mut added = false;
+    let msg = update_message(message_id, |m| {
+        if m.unsent { return Err("Message was unsent".to_string()); }
+        match m.reactions.iter_mut().find(|r| r.emoji == emoji) {
+            Some(reaction) => {
+                if !reaction.users.contains(&me) {
+                    reaction.users.push(me);
+                    added = true;
+                }
+            }
+            None => {
+                if m.reactions.len() >= MAX_REACTIONS_PER_MESSAGE {
+                    return Err(format!("A message can have at most {} different reactions", MAX_REACTIONS_PER_MESSAGE));
+                }
+                m.reactions.push(Reaction { emoji: emoji.clone(), users: vec![me] });
+                added = true;
+            }
+        }
+        Ok(())
+    })?;
+
+    if added {
+        let kind = EventKind::ReactionAdded { message_id, conversation_id: msg.conversation_id, by: me };
+        events::record_private(kind, member_ids(msg.conversation_id));
+    }
+    if added && msg.from != me {
+        let _ = add_notification_internal(
+            me,
+            msg.from,
+            NotificationType::Reaction,
+            format!("reacted {} to your message", emoji),
+            NotificationTarget::MessageId { message_id, conversation_id: msg.conversation_id, peer: me },
+        );
+    }
+    Ok(msg)
+}
+
+#[ic_cdk::update]
+pub fn remove_reaction(message_id: u64, emoji: String) -> Result<Message, String> {
+    let me = auth::registered_caller()?;
+    let emoji = validation::clean_emoji(&emoji)?;
+    if !can_view_message(me, message_id) {
+        return Err("Message not found".to_string());
+    }
+    let mut removed = false;
+    let msg = update_message(message_id, |m| {
+        if let Some(reaction) = m.reactions.iter_mut().find(|r| r.emoji == emoji) {
+            removed = reaction.users.contains(&me);
+            reaction.users.retain(|p| *p != me);
+        }
+        m.reactions.retain(|r| !r.users.is_empty());
+        Ok(())
+    })?;
+    if removed {
+        let kind = EventKind::ReactionRemoved { message_id, conversation_id: msg.conversation_id, by: me };
+        events::record_private(kind, member_ids(msg.conversation_id));
+    }
+
+    // the notification goes once the user has no reaction left on the message
+    if !msg.reactions.iter().any(|r| r.users.contains(&me)) {
+        let target = NotificationTarget::MessageId { message_id, conversation_id: msg.conversation_id, peer: me };
+        retract_notification(me, msg.from, NotificationType::Reaction, target);
+    }
+    Ok(msg)
+}
+
+// Disappearing messages
+
+/// Turn disappearing messages on or off for a conversation. Applies to
+/// messages sent from now on; in groups only admins can change it.
+#[ic_cdk::update]
+pub fn set_disappearing_messages(conversation_id: u64, setting: DisappearAfter) -> Result<Conversation, String> {
+    let me = auth::registered_caller()?;
+    let mut conversation = CONVERSATIONS
+        .with(|c| c.borrow().get(&conversation_id).cloned())
+        .ok_or("Conversation not found")?;
+    if !is_member(&conversation, me) {
+        return Err("You are not a member of this conversation".to_string());
+    }
+    // the other person in a direct conversation; the notice is addressed to them
+    let peer = match conversation.kind {
+        ConversationKind::Group if !is_admin(&conversation, me) => {
+            return Err("Only group admins can change disappearing messages".to_string());
+        }
+        ConversationKind::Group => None,
+        ConversationKind::Direct => {
+            let peer = conversation.members.iter().map(|m| m.user_principal).find(|p| *p != me);
+            if peer.is_none_or(|peer| dm_access(me, peer) != DmAccess::Allowed) {
+                return Err("You cannot change settings of this conversation".to_string());
+            }
+            peer
+        }
+    };
+    if conversation.disappear_after == setting {
+        return Ok(conversation);
+    }
+
+    conversation.disappear_after = setting;
+    save_conversation(conversation);
+    let kind = EventKind::DisappearingMessagesChanged { conversation_id, by: me };
+    events::record_private(kind, member_ids(conversation_id));
+
+    let content = match setting {
+        DisappearAfter::Off => "turned off disappearing messages".to_string(),
+        on => format!("set messages to disappear after {}", on.label()),
+    };
+    let mut notice = new_message(conversation_id, me, peer, Body::Plain(content), None);
+    // the notice itself stays so members can see when the setting changed
+    notice.expires_at = None;
+    notice.system = true;
+    append_message(&notice);
+
+    CONVERSATIONS
+        .with(|c| c.borrow().get(&conversation_id).cloned())
+        .ok_or_else(|| "Conversation not found".to_string())
+}
+
+/// Purge expired messages every `PURGE_INTERVAL`, `MAX_PURGE_BATCH` at a time
+pub fn start_expiry_timer() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, || purge_expired(time(), MAX_PURGE_BATCH));
+}
+
+/// Delete up to `limit` messages whose expiry is at or before `now`
+fn purge_expired(now: u64, limit: usize) {
+    let due: Vec<(u64, u64)> = EXPIRY_QUEUE.with(|q| {
+        q.borrow().range(..=(now, u64::MAX)).take(limit).copied().collect()
+    });
+    for key in due {
+        EXPIRY_QUEUE.with(|q| q.borrow_mut().remove(&key));
+        purge_message(key.1);
+    }
+}
+
+/// Remove a message for everyone, fixing up unread counts and the preview
+fn purge_message(message_id: u64) {
+    let Some(conversation_id) = MESSAGE_INDEX.with(|i| i.borrow_mut().remove(&message_id)) else { return };
+    let removed = MESSAGES.with(|mm| {
+        let mut mm = mm.borrow_mut();
+        let list = mm.get_mut(&conversation_id)?;
+        let pos = list.binary_search_by_key(&message_id, |m| m.id).ok()?;
+        Some(list.remove(pos))
+    });
+    let Some(msg) = removed else { return };
+    attachments::delete(&msg.attachments);
+    sync::touch(Change::Message { message_id, conversation_id });
+
+    let members: Vec<Principal> = CONVERSATIONS.with(|c| {
+        let mut c = c.borrow_mut();
+        let Some(conversation) = c.get_mut(&conversation_id) else { return Vec::new() };
+        if conversation.last_message.as_ref().map(|p| p.message_id) == Some(message_id) {
+            conversation.last_message = MESSAGES.with(|mm| {
+                mm.borrow().get(&conversation_id).and_then(|list| list.last()).map(preview_of)
+            });
+        }
+        conversation.members.iter().map(|m| m.user_principal).collect()
+    });
+    events::record_private(EventKind::MessageExpired { message_id, conversation_id }, members.clone());
+
+    for member in members {
+        HIDDEN_MESSAGES.with(|h| h.borrow_mut().remove(&(member, message_id)));
+        if member == msg.from {
+            continue;
+        }
+        update_member_state(member, conversation_id, |state| {
+            if msg.id > state.last_read_id {
+                state.unread_count = state.unread_count.saturating_sub(1);
+            }
+        });
+    }
+}
+
+// Groups
+
+#[ic_cdk::update]
+pub fn create_group(title: String, members: Vec<Principal>) -> Result<Conversation, String> {
+    let me = auth::registered_caller()?;
+    let title = validation::clean_required_text(&title, &validation::GROUP_TITLE)?;
+
+    let mut others: Vec<Principal> = Vec::new();
+    for p in members {
+        if p != me && !others.contains(&p) { others.push(p); }
+    }
+    if others.is_empty() {
+        return Err("A group needs at least one other member".to_string());
+    }
+    if others.len() + 1 > MAX_GROUP_MEMBERS {
+        return Err(format!("A group can have at most {} members", MAX_GROUP_MEMBERS));
+    }
+    check_new_members(me, &others)?;
+
+    let now = time();
+    let id = next_conversation_id();
+    let mut member_list = vec![ConversationMember { user_principal: me, role: MemberRole::Admin, joined_at: now }];
+    member_list.extend(
+        others
+            .iter()
+            .map(|p| ConversationMember { user_principal: *p, role: MemberRole::Member, joined_at: now }),
+    );
+
+    let conversation = Conversation {
+        conversation_id: id,
+        kind: ConversationKind::Group,
+        title: Some(title),
+        members: member_list,
+        created_by: me,
+        created_at: now,
+        last_message_at: now,
+        last_message: None,
+        disappear_after: DisappearAfter::Off,
+    };
+    save_conversation(conversation.clone());
+    add_member_state(me, id, now, Folder::Inbox);
+    for p in others { add_member_state(p, id, now, Folder::Inbox); }
+    events::record_private(EventKind::GroupCreated { conversation_id: id, created_by: me }, member_ids(id));
+
+    Ok(conversation)
+}
+
+#[ic_cdk::query]
+pub fn get_group(conversation_id: u64) -> Result<Conversation, String> {
+    get_group_as_member(conversation_id, caller())
+}
+
+/// Admins only
+#[ic_cdk::update]
+pub fn add_group_members(conversation_id: u64, members: Vec<Principal>) -> Result<Conversation, String> {
+    let me = auth::registered_caller()?;
+    let mut conversation = get_group_as_member(conversation_id, me)?;
+    if !is_admin(&conversation, me) {
+        return Err("Only group admins can add members".to_string());
+    }
+
+    let mut new_members: Vec<Principal> = Vec::new();
+    for p in members {
+        if !is_member(&conversation, p) && !new_members.contains(&p) { new_members.push(p); }
+    }
+    if new_members.is_empty() {
+        return Ok(conversation);
+    }
+    if conversation.members.len() + new_members.len() > MAX_GROUP_MEMBERS {
+        return Err(format!("A group can have at most {} members", MAX_GROUP_MEMBERS));
+    }
+    check_new_members(me, &new_members)?;
+
+    let now = time();
+    // new members only see messages sent after they joined
+    let last_id = last_message_id(conversation_id);
+    for p in &new_members {
+        conversation.members.push(ConversationMember { user_principal: *p, role: MemberRole::Member, joined_at: now });
+        add_member_state(*p, conversation_id, now, Folder::Inbox);
+        update_member_state(*p, conversation_id, |state| state.cleared_up_to = last_id);
+    }
+    save_conversation(conversation.clone());
+    let kind = EventKind::GroupMembersAdded { conversation_id, added_by: me, members: new_members };
+    events::record_private(kind, member_ids(conversation_id));
+    Ok(conversation)
+}
+
+/// Admins only; use `leave_group` to remove yourself
+#[ic_cdk::update]
+pub fn remove_group_member(conversation_id: u64, member: Principal) -> Result<Conversation, String> {
+    let me = auth::registered_caller()?;
+    let mut conversation = get_group_as_member(conversation_id, me)?;
+    if !is_admin(&conversation, me) {
+        return Err("Only group admins can remove members".to_string());
+    }
+    if member == me {
+        return Err("Use leave_group to leave a group".to_string());
+    }
+    if !is_member(&conversation, member) {
+        return Err("User is not a member of this group".to_string());
+    }
+
+    conversation.members.retain(|m| m.user_principal != member);
+    remove_member_state(member, conversation_id);
+    save_conversation(conversation.clone());
+    let mut audience = member_ids(conversation_id);
+    audience.push(member);
+    events::record_private(EventKind::GroupMemberRemoved { conversation_id, removed_by: me, member }, audience);
+    Ok(conversation)
+}
+
+/// Promote or demote a member. A group always keeps at least one admin.
+#[ic_cdk::update]
+pub fn set_group_admin(conversation_id: u64, member: Principal, admin: bool) -> Result<Conversation, String> {
+    let me = auth::registered_caller()?;
+    let mut conversation = get_group_as_member(conversation_id, me)?;
+    if !is_admin(&conversation, me) {
+        return Err("Only group admins can change roles".to_string());
+    }
+    let admin_count = conversation.members.iter().filter(|m| m.role == MemberRole::Admin).count();
+
+    let target = conversation
+        .members
+        .iter_mut()
+        .find(|m| m.user_principal == member)
+        .ok_or("User is not a member of this group")?;
+    if !admin && target.role == MemberRole::Admin && admin_count == 1 {
+        return Err("A group must keep at least one admin".to_string());
+    }
+    target.role = if admin { MemberRole::Admin } else { MemberRole::Member };
+
+    save_conversation(conversation.clone());
+    let kind = EventKind::GroupAdminChanged { conversation_id, changed_by: me, member, admin };
+    events::record_private(kind, member_ids(conversation_id));
+    Ok(conversation)
+}
+
+/// Leave a group. The longest-standing member is promoted if the last admin leaves,
+/// and the group is deleted once nobody is left.
+#[ic_cdk::update]
+pub fn leave_group(conversation_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    let mut conversation = get_group_as_member(conversation_id, me)?;
+
+    conversation.members.retain(|m| m.user_principal != me);
+    remove_member_state(me, conversation_id);
+    let mut audience: Vec<Principal> = conversation.members.iter().map(|m| m.user_principal).collect();
+    audience.push(me);
+    events::record_private(EventKind::GroupLeft { conversation_id, member: me }, audience);
+
+    if conversation.members.is_empty() {
+        CONVERSATIONS.with(|c| c.borrow_mut().remove(&conversation_id));
+        let messages = MESSAGES.with(|mm| mm.borrow_mut().remove(&conversation_id)).unwrap_or_default();
+        for msg in &messages {
+            MESSAGE_INDEX.with(|i| i.borrow_mut().remove(&msg.id));
+            if let Some(at) = msg.expires_at {
+                EXPIRY_QUEUE.with(|q| q.borrow_mut().remove(&(at, msg.id)));
+            }
+            attachments::delete(&msg.attachments);
+        }
+        // hidden by members who have since left too, so not keyed by anyone still here
+        let ids: BTreeSet<u64> = messages.iter().map(|m| m.id).collect();
+        HIDDEN_MESSAGES.with(|h| h.borrow_mut().retain(|(_, id)| !ids.contains(id)));
+        return Ok("Left group".to_string());
+    }
+
+    if !conversation.members.iter().any(|m| m.role == MemberRole::Admin) {
+        if let Some(oldest) = conversation.members.iter_mut().min_by_key(|m| m.joined_at) {
+            oldest.role = MemberRole::Admin;
+            let kind =
+                EventKind::GroupAdminChanged { conversation_id, changed_by: me, member: oldest.user_principal, admin: true };
+            let audience = conversation.members.iter().map(|m| m.user_principal).collect();
+            events::record_private(kind, audience);
+        }
+    }
+    save_conversation(conversation);
+    Ok("Left group".to_string())
+}
+
+#[ic_cdk::update]
+pub fn send_group_message(conversation_id: u64, content: String, reply_to: Option<u64>) -> Result<Message, String> {
+    send_to_group(conversation_id, Body::Plain(content), reply_to)
+}
+
+/// Group variant of `send_encrypted_message`; needs a wrapped key for every current member
+#[ic_cdk::update]
+pub fn send_encrypted_group_message(
+    conversation_id: u64,
+    payload: EncryptedPayload,
+    reply_to: Option<u64>,
+) -> Result<Message, String> {
+    send_to_group(conversation_id, Body::Encrypted(payload), reply_to)
+}
+
+fn send_to_group(conversation_id: u64, body: Body, reply_to: Option<u64>) -> Result<Message, String> {
+    let me = auth::registered_caller()?;
+    let conversation = get_group_as_member(conversation_id, me)?;
+    let members: Vec<Principal> = conversation.members.iter().map(|m| m.user_principal).collect();
+    let body = body.check(&members, false)?;
+    check_reply_to(me, conversation_id, reply_to)?;
+
+    let msg = new_message(conversation_id, me, None, body, reply_to);
+    append_message(&msg);
+    Ok(msg)
+}
+
+/// Group variant of `get_messages`
+#[ic_cdk::query]
+pub fn get_group_messages(conversation_id: u64, before_id: Option<u64>, limit: u32) -> Result<Vec<Message>, String> {
+    let me = caller();
+    get_group_as_member(conversation_id, me)?;
+    Ok(messages_before(me, conversation_id, before_id, limit))
+}
+
+/// Group variant of `get_messages_since`
+#[ic_cdk::query]
+pub fn get_group_messages_since(conversation_id: u64, after_id: u64) -> Result<Vec<Message>, String> {
+    let me = caller();
+    get_group_as_member(conversation_id, me)?;
+    Ok(messages_since(me, conversation_id, after_id))
+}
@@ -0,0 +1,889 @@
+// Notifications, stored one per event and shown grouped.
+//
+// Every notification belongs to a group keyed by (receiver, type, what it is
+// about), e.g. all likes on one post. Groups are maintained as notifications
+// come and go, so the grouped view pages through `GROUP_ORDER` directly.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, time};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::time::Duration;
+
+use crate::digest::{self, DigestSummary};
+use crate::events::{self, AdminSetting, EventKind, Setting};
+use crate::sync::{self, Change};
+use crate::{auth, USERS};
+
+pub const MAX_NOTIFICATION_PAGE: u32 = 50;
+pub const MAX_GROUP_PAGE: u32 = 50;
+/// Actors listed on a group; `actor_count` has the full number
+pub const MAX_GROUP_ACTORS: usize = 10;
+pub const MAX_MIN_ACCOUNT_AGE_DAYS: u32 = 365;
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Notifications removed or checked per step of the prune job
+pub const PRUNE_BATCH: usize = 500;
+/// The prune job stops well below the per-message instruction limit
+pub const PRUNE_INSTRUCTION_BUDGET: u64 = 2_000_000_000;
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * 60 * NANOS_PER_MINUTE;
+
+// Data Structures
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub notification_id: u64,
+    pub sender: Principal,
+    pub receiver: Principal,
+    pub notification_type: NotificationType,
+    pub message: String,
+    /// What the notification is about, for deep links
+    pub target: NotificationTarget,
+    pub created_at: u64,
+    pub read: bool,
+    /// The figures behind a `Digest` notification
+    pub digest: Option<DigestSummary>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NotificationTarget {
+    PostId(u64),
+    CommentId { post_id: u64, comment_id: u64 },
+    /// `peer` is the other side of the conversation from the receiver's point of view
+    MessageId { message_id: u64, conversation_id: u64, peer: Principal },
+    Profile(Principal),
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NotificationType {
+    Like,
+    Comment,
+    Follow,
+    Repost,
+    Message,
+    Reaction,
+    /// Periodic summary, sent by the canister itself
+    Digest,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum NotificationSetting {
+    #[default]
+    On,
+    Off,
+    /// Only from people the receiver follows
+    OnlyFollowing,
+}
+
+/// A daily window in the user's local time, which may wrap past midnight
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct QuietHours {
+    /// Minutes after local midnight
+    pub start_minute: u16,
+    pub end_minute: u16,
+    /// Local time minus UTC, in minutes
+    pub utc_offset_minutes: i16,
+}
+
+impl QuietHours {
+    fn contains(&self, now: u64) -> bool {
+        let minute = ((now / NANOS_PER_MINUTE) as i64 + self.utc_offset_minutes as i64).rem_euclid(MINUTES_PER_DAY);
+        let (start, end) = (self.start_minute as i64, self.end_minute as i64);
+        if start <= end {
+            start <= minute && minute < end
+        } else {
+            minute >= start || minute < end
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default)]
+pub struct NotificationPreferences {
+    pub like: NotificationSetting,
+    pub comment: NotificationSetting,
+    pub follow: NotificationSetting,
+    pub repost: NotificationSetting,
+    pub message: NotificationSetting,
+    pub reaction: NotificationSetting,
+    /// Nothing is delivered during quiet hours
+    pub quiet_hours: Option<QuietHours>,
+    /// Ignore senders whose account is younger than this
+    pub min_account_age_days: Option<u32>,
+}
+
+impl NotificationPreferences {
+    fn setting(&self, notification_type: NotificationType) -> NotificationSetting {
+        match notification_type {
+            NotificationType::Like => self.like,
+            NotificationType::Comment => self.comment,
+            NotificationType::Follow => self.follow,
+            NotificationType::Repost => self.repost,
+            NotificationType::Message => self.message,
+            NotificationType::Reaction => self.reaction,
+            // opted into separately with `set_digest_frequency`
+            NotificationType::Digest => NotificationSetting::On,
+        }
+    }
+
+    /// Whether `receiver` wants this notification from `sender` right now
+    fn allows(&self, sender: Principal, receiver: Principal, notification_type: NotificationType, now: u64) -> bool {
+        let follows_sender = || {
+            USERS.with(|u| u.borrow().get(&receiver).map(|user| user.following.contains(&sender)).unwrap_or(false))
+        };
+        match self.setting(notification_type) {
+            NotificationSetting::Off => return false,
+            NotificationSetting::OnlyFollowing if !follows_sender() => return false,
+            _ => {}
+        }
+        if self.quiet_hours.map(|q| q.contains(now)).unwrap_or(false) {
+            return false;
+        }
+        if let Some(days) = self.min_account_age_days {
+            let created_at = USERS.with(|u| u.borrow().get(&sender).map(|user| user.created_at)).unwrap_or(now);
+            if now.saturating_sub(created_at) < days as u64 * NANOS_PER_DAY {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// How long notifications are kept, set by admins
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    pub max_age_days: u32,
+    /// The oldest notifications of a receiver go first beyond this
+    pub max_per_receiver: u32,
+}
+
+/// Notifications of one type about the same thing, e.g. every like on a post
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NotificationGroup {
+    pub group_id: u64,
+    pub notification_type: NotificationType,
+    /// Target of the latest notification in the group
+    pub target: NotificationTarget,
+    /// Distinct senders, most recent first, at most `MAX_GROUP_ACTORS`
+    pub actors: Vec<Principal>,
+    pub actor_count: u32,
+    /// e.g. "Alice and 23 others liked your post"
+    pub summary: String,
+    pub latest_at: u64,
+    pub unread: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct NotificationGroupCursor {
+    pub latest_at: u64,
+    pub group_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NotificationGroupPage {
+    pub groups: Vec<NotificationGroup>,
+    pub next_cursor: Option<NotificationGroupCursor>,
+}
+
+/// What notifications are grouped by, besides receiver and type
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum GroupTarget {
+    Post(u64),
+    Message(u64),
+    Conversation(u64),
+    /// Follows are grouped per receiver
+    Account,
+    Digest,
+}
+
+impl GroupTarget {
+    fn of(notification_type: NotificationType, target: NotificationTarget) -> GroupTarget {
+        match (notification_type, target) {
+            (NotificationType::Digest, _) => GroupTarget::Digest,
+            (NotificationType::Follow, _) | (_, NotificationTarget::Profile(_)) => GroupTarget::Account,
+            (_, NotificationTarget::PostId(post_id)) => GroupTarget::Post(post_id),
+            // all comments on a post form one group
+            (_, NotificationTarget::CommentId { post_id, .. }) => GroupTarget::Post(post_id),
+            (NotificationType::Message, NotificationTarget::MessageId { conversation_id, .. }) => {
+                GroupTarget::Conversation(conversation_id)
+            }
+            (_, NotificationTarget::MessageId { message_id, .. }) => GroupTarget::Message(message_id),
+        }
+    }
+}
+
+type GroupKey = (Principal, NotificationType, GroupTarget);
+
+#[derive(Clone, Debug)]
+struct GroupState {
+    key: GroupKey,
+    /// Oldest first
+    notification_ids: Vec<u64>,
+    latest_at: u64,
+    unread: u32,
+}
+
+// Storage
+thread_local! {
+    static NOTIFICATIONS: RefCell<BTreeMap<u64, Notification>> = const { RefCell::new(BTreeMap::new()) };
+    // (receiver, notification id); ids grow over time, so this is each receiver's history in order
+    static RECEIVER_INDEX: RefCell<BTreeSet<(Principal, u64)>> = const { RefCell::new(BTreeSet::new()) };
+    static UNREAD_COUNTS: RefCell<BTreeMap<Principal, u64>> = const { RefCell::new(BTreeMap::new()) };
+    static TOTAL_COUNTS: RefCell<BTreeMap<Principal, u32>> = const { RefCell::new(BTreeMap::new()) };
+    static RETENTION: RefCell<RetentionPolicy> =
+        const { RefCell::new(RetentionPolicy { max_age_days: 90, max_per_receiver: 500 }) };
+    // Last notification id checked by the sweep for deleted senders
+    static SWEEP_CURSOR: RefCell<u64> = const { RefCell::new(0) };
+    // Last receiver checked against the count cap
+    static CAP_CURSOR: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    static PREFERENCES: RefCell<BTreeMap<Principal, NotificationPreferences>> = const { RefCell::new(BTreeMap::new()) };
+    static GROUPS: RefCell<BTreeMap<u64, GroupState>> = const { RefCell::new(BTreeMap::new()) };
+    static GROUP_INDEX: RefCell<BTreeMap<GroupKey, u64>> = const { RefCell::new(BTreeMap::new()) };
+    // (receiver, latest_at, group id): each receiver's groups in recency order
+    static GROUP_ORDER: RefCell<BTreeSet<(Principal, u64, u64)>> = const { RefCell::new(BTreeSet::new()) };
+
+    static NOTIFICATION_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static GROUP_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// Helpers
+
+fn get_next_notification_id() -> u64 {
+    NOTIFICATION_COUNTER.with(|counter| {
+        let mut count = counter.borrow_mut();
+        *count += 1;
+        *count
+    })
+}
+
+fn preferences(user: Principal) -> NotificationPreferences {
+    PREFERENCES.with(|p| p.borrow().get(&user).copied().unwrap_or_default())
+}
+
+fn get_next_group_id() -> u64 {
+    GROUP_COUNTER.with(|counter| {
+        let mut count = counter.borrow_mut();
+        *count += 1;
+        *count
+    })
+}
+
+/// File a new notification under its group, creating the group if needed
+fn add_to_group(notification: &Notification) {
+    let key = (
+        notification.receiver,
+        notification.notification_type,
+        GroupTarget::of(notification.notification_type, notification.target),
+    );
+    let group_id = GROUP_INDEX.with(|i| i.borrow().get(&key).copied()).unwrap_or_else(|| {
+        let id = get_next_group_id();
+        GROUP_INDEX.with(|i| i.borrow_mut().insert(key, id));
+        GROUPS.with(|g| {
+            g.borrow_mut().insert(id, GroupState { key, notification_ids: Vec::new(), latest_at: 0, unread: 0 })
+        });
+        id
+    });
+
+    GROUPS.with(|g| {
+        if let Some(group) = g.borrow_mut().get_mut(&group_id) {
+            GROUP_ORDER.with(|o| {
+                let mut o = o.borrow_mut();
+                o.remove(&(key.0, group.latest_at, group_id));
+                o.insert((key.0, notification.created_at, group_id));
+            });
+            group.notification_ids.push(notification.notification_id);
+            group.latest_at = notification.created_at;
+            group.unread += 1;
+        }
+    });
+}
+
+/// Take a notification out of its group; an emptied group is dropped
+fn remove_from_group(notification: &Notification) {
+    let Some(group_id) = group_id_of(notification) else { return };
+
+    GROUPS.with(|g| {
+        let mut g = g.borrow_mut();
+        let Some(group) = g.get_mut(&group_id) else { return };
+        group.notification_ids.retain(|id| *id != notification.notification_id);
+        if !notification.read {
+            group.unread = group.unread.saturating_sub(1);
+        }
+
+        let latest_at = group
+            .notification_ids
+            .last()
+            .and_then(|id| NOTIFICATIONS.with(|n| n.borrow().get(id).map(|n| n.created_at)));
+        GROUP_ORDER.with(|o| {
+            let mut o = o.borrow_mut();
+            o.remove(&(notification.receiver, group.latest_at, group_id));
+            if let Some(at) = latest_at {
+                o.insert((notification.receiver, at, group_id));
+            }
+        });
+        match latest_at {
+            Some(at) => group.latest_at = at,
+            None => {
+                let key = group.key;
+                g.remove(&group_id);
+                GROUP_INDEX.with(|i| i.borrow_mut().remove(&key));
+            }
+        }
+    });
+}
+
+fn adjust_unread(receiver: Principal, delta: i64) {
+    UNREAD_COUNTS.with(|c| {
+        let mut c = c.borrow_mut();
+        let count = c.entry(receiver).or_default();
+        *count = count.saturating_add_signed(delta);
+        if *count == 0 { c.remove(&receiver); }
+    });
+}
+
+fn group_id_of(notification: &Notification) -> Option<u64> {
+    let key = (
+        notification.receiver,
+        notification.notification_type,
+        GroupTarget::of(notification.notification_type, notification.target),
+    );
+    GROUP_INDEX.with(|i| i.borrow().get(&key).copied())
+}
+
+/// Mark one notification read, keeping its group and the unread counter in step.
+/// Returns false if it was already read.
+fn set_read(notification_id: u64) -> bool {
+    let notification = NOTIFICATIONS.with(|n| {
+        let mut n = n.borrow_mut();
+        let notification = n.get_mut(&notification_id).filter(|n| !n.read)?;
+        notification.read = true;
+        Some(notification.clone())
+    });
+    let Some(notification) = notification else { return false };
+
+    adjust_unread(notification.receiver, -1);
+    sync::touch(Change::Notification { notification_id, receiver: notification.receiver });
+    if let Some(group_id) = group_id_of(&notification) {
+        GROUPS.with(|g| {
+            if let Some(group) = g.borrow_mut().get_mut(&group_id) {
+                group.unread = group.unread.saturating_sub(1);
+            }
+        });
+    }
+    true
+}
+
+fn adjust_total(receiver: Principal, delta: i32) -> u32 {
+    TOTAL_COUNTS.with(|c| {
+        let mut c = c.borrow_mut();
+        let count = c.entry(receiver).or_default();
+        *count = count.saturating_add_signed(delta);
+        let total = *count;
+        if total == 0 { c.remove(&receiver); }
+        total
+    })
+}
+
+/// Delete a notification and keep its group and indexes in step
+fn remove_notification(notification_id: u64) -> Option<Notification> {
+    let notification = NOTIFICATIONS.with(|n| n.borrow_mut().remove(&notification_id))?;
+    RECEIVER_INDEX.with(|i| i.borrow_mut().remove(&(notification.receiver, notification_id)));
+    adjust_total(notification.receiver, -1);
+    if !notification.read {
+        adjust_unread(notification.receiver, -1);
+    }
+    remove_from_group(&notification);
+    sync::touch(Change::Notification { notification_id, receiver: notification.receiver });
+    Some(notification)
+}
+
+/// The receiver's notification ids up to `up_to`, oldest first
+fn ids_of(receiver: Principal, up_to: u64) -> Vec<u64> {
+    RECEIVER_INDEX.with(|i| i.borrow().range((receiver, 0)..=(receiver, up_to)).map(|(_, id)| *id).collect())
+}
+
+fn user_name(user: Principal) -> String {
+    USERS.with(|u| u.borrow().get(&user).map(|p| p.name.clone())).unwrap_or_else(|| "Someone".to_string())
+}
+
+fn group_view(group_id: u64, group: &GroupState) -> Option<NotificationGroup> {
+    let notifications: Vec<Notification> = NOTIFICATIONS.with(|n| {
+        let n = n.borrow();
+        group.notification_ids.iter().filter_map(|id| n.get(id).cloned()).collect()
+    });
+    let latest = notifications.last()?;
+
+    let mut actors: Vec<Principal> = Vec::new();
+    for n in notifications.iter().rev() {
+        if !actors.contains(&n.sender) { actors.push(n.sender); }
+    }
+    let actor_count = actors.len() as u32;
+    let who = match actors.as_slice() {
+        [one] => user_name(*one),
+        [first, second] => format!("{} and {}", user_name(*first), user_name(*second)),
+        [first, rest @ ..] => format!("{} and {} others", user_name(*first), rest.len()),
+        [] => return None,
+    };
+    actors.truncate(MAX_GROUP_ACTORS);
+    let summary = match latest.notification_type {
+        // the digest text stands on its own; its sender is the canister
+        NotificationType::Digest => latest.message.clone(),
+        _ => format!("{} {}", who, latest.message),
+    };
+
+    Some(NotificationGroup {
+        group_id,
+        notification_type: latest.notification_type,
+        target: latest.target,
+        actors,
+        actor_count,
+        summary,
+        latest_at: group.latest_at,
+        unread: group.unread > 0,
+    })
+}
+
+// Internal API
+
+/// Store a notification unless the receiver's preferences filter it out.
+/// Reads `USERS`, so callers must not hold a mutable borrow of it.
+pub(crate) fn add_notification_internal(
+    sender: Principal,
+    receiver: Principal,
+    notification_type: NotificationType,
+    message: String,
+    target: NotificationTarget,
+) -> Result<Notification, String> {
+    let now = time();
+    // digests count activity whether or not it is delivered
+    digest::record(sender, receiver, notification_type, target);
+    if !preferences(receiver).allows(sender, receiver, notification_type, now) {
+        return Err("Filtered by the receiver's notification preferences".to_string());
+    }
+    Ok(store_notification(sender, receiver, notification_type, message, target, None))
+}
+
+/// Store a notification as-is, without checking preferences
+pub(crate) fn store_notification(
+    sender: Principal,
+    receiver: Principal,
+    notification_type: NotificationType,
+    message: String,
+    target: NotificationTarget,
+    digest: Option<DigestSummary>,
+) -> Notification {
+    let notification_id = get_next_notification_id();
+    let notification = Notification {
+        notification_id,
+        sender,
+        receiver,
+        notification_type,
+        message,
+        target,
+        created_at: time(),
+        read: false,
+        digest,
+    };
+
+    NOTIFICATIONS.with(|notifications| {
+        notifications.borrow_mut().insert(notification_id, notification.clone());
+    });
+    RECEIVER_INDEX.with(|i| i.borrow_mut().insert((receiver, notification_id)));
+    adjust_unread(receiver, 1);
+    add_to_group(&notification);
+    sync::touch(Change::Notification { notification_id, receiver });
+
+    adjust_total(receiver, 1);
+    trim_to_cap(receiver, RETENTION.with(|r| r.borrow().max_per_receiver));
+
+    notification
+}
+
+/// Drop the receiver's oldest notifications beyond `cap`
+fn trim_to_cap(receiver: Principal, cap: u32) {
+    let total = TOTAL_COUNTS.with(|t| t.borrow().get(&receiver).copied().unwrap_or(0));
+    let excess = total.saturating_sub(cap) as usize;
+    if excess == 0 {
+        return;
+    }
+    let oldest: Vec<u64> = RECEIVER_INDEX.with(|i| {
+        i.borrow().range((receiver, 0)..=(receiver, u64::MAX)).take(excess).map(|(_, id)| *id).collect()
+    });
+    for id in oldest {
+        remove_notification(id);
+    }
+}
+
+pub(crate) fn notification(notification_id: u64) -> Option<Notification> {
+    NOTIFICATIONS.with(|n| n.borrow().get(&notification_id).cloned())
+}
+
+/// Drop notifications about a deleted post: its likes, comments and reposts
+pub(crate) fn on_post_deleted(author: Principal, post_id: u64) {
+    for notification_type in [NotificationType::Like, NotificationType::Comment, NotificationType::Repost] {
+        let key = (author, notification_type, GroupTarget::Post(post_id));
+        let Some(group_id) = GROUP_INDEX.with(|i| i.borrow().get(&key).copied()) else { continue };
+        let ids = GROUPS.with(|g| g.borrow().get(&group_id).map(|group| group.notification_ids.clone()));
+        for id in ids.unwrap_or_default() {
+            remove_notification(id);
+        }
+    }
+}
+
+// Retention
+
+pub fn start_prune_timer() {
+    ic_cdk_timers::set_timer_interval(PRUNE_INTERVAL, || prune(time()));
+}
+
+fn over_budget() -> bool {
+    ic_cdk::api::instruction_counter() > PRUNE_INSTRUCTION_BUDGET
+}
+
+/// Remove notifications past the max age, then check the next batch for
+/// senders whose account no longer exists, then the next batch of receivers
+/// against the count cap. Stops early near the budget; the next run picks up
+/// where this one left off.
+fn prune(now: u64) {
+    let max_age = RETENTION.with(|r| r.borrow().max_age_days) as u64 * NANOS_PER_DAY;
+    let cutoff = now.saturating_sub(max_age);
+    // ids grow over time, so the expired ones are at the front
+    while !over_budget() {
+        let expired: Vec<u64> = NOTIFICATIONS.with(|n| {
+            n.borrow()
+                .values()
+                .take_while(|n| n.created_at < cutoff)
+                .take(PRUNE_BATCH)
+                .map(|n| n.notification_id)
+                .collect()
+        });
+        if expired.is_empty() {
+            break;
+        }
+        for id in expired {
+            remove_notification(id);
+        }
+    }
+    if over_budget() {
+        return;
+    }
+
+    let after = SWEEP_CURSOR.with(|c| *c.borrow());
+    let batch: Vec<(u64, Principal)> = NOTIFICATIONS.with(|n| {
+        n.borrow()
+            .range(after + 1..)
+            .take(PRUNE_BATCH)
+            // digests come from the canister, not a user
+            .map(|(id, n)| (*id, if n.notification_type == NotificationType::Digest { n.receiver } else { n.sender }))
+            .collect()
+    });
+    // wrap around once the end is reached
+    let next = if batch.len() < PRUNE_BATCH { 0 } else { batch.last().map(|(id, _)| *id).unwrap_or(0) };
+    SWEEP_CURSOR.with(|c| *c.borrow_mut() = next);
+    for (id, sender) in batch {
+        if !USERS.with(|u| u.borrow().contains_key(&sender)) {
+            remove_notification(id);
+        }
+    }
+
+    // new notifications trim their receiver, but a lowered cap also has to
+    // reach receivers who get none
+    let cap = RETENTION.with(|r| r.borrow().max_per_receiver);
+    let after = CAP_CURSOR.with(|c| *c.borrow());
+    let receivers: Vec<(Principal, u32)> = TOTAL_COUNTS.with(|t| {
+        let t = t.borrow();
+        let from = after.map(Bound::Excluded).unwrap_or(Bound::Unbounded);
+        t.range((from, Bound::Unbounded)).take(PRUNE_BATCH).map(|(p, n)| (*p, *n)).collect()
+    });
+    let mut next = if receivers.len() < PRUNE_BATCH { None } else { receivers.last().map(|(p, _)| *p) };
+    let mut done = after;
+    for (receiver, total) in receivers {
+        if over_budget() {
+            next = done;
+            break;
+        }
+        if total > cap {
+            trim_to_cap(receiver, cap);
+        }
+        done = Some(receiver);
+    }
+    CAP_CURSOR.with(|c| *c.borrow_mut() = next);
+}
+
+/// Withdraw what `sender` caused, e.g. on unlike, so toggling doesn't pile up duplicates
+pub(crate) fn retract_notification(
+    sender: Principal,
+    receiver: Principal,
+    notification_type: NotificationType,
+    target: NotificationTarget,
+) {
+    digest::unrecord(sender, receiver, notification_type, target);
+    let key = (receiver, notification_type, GroupTarget::of(notification_type, target));
+    let Some(group_id) = GROUP_INDEX.with(|i| i.borrow().get(&key).copied()) else { return };
+    let ids = GROUPS.with(|g| g.borrow().get(&group_id).map(|group| group.notification_ids.clone())).unwrap_or_default();
+    let matching: Vec<u64> = NOTIFICATIONS.with(|n| {
+        let n = n.borrow();
+        ids.into_iter()
+            .filter(|id| n.get(id).map(|n| n.sender == sender && n.target == target).unwrap_or(false))
+            .collect()
+    });
+    for id in matching {
+        remove_notification(id);
+    }
+}
+
+// Endpoints
+
+/// The caller's notifications, newest first. Pass the last id of a page as
+/// `cursor` to get older ones; a short page means there are no more.
+#[ic_cdk::query]
+pub fn get_notifications(
+    cursor: Option<u64>,
+    limit: Option<u32>,
+    notification_type: Option<NotificationType>,
+) -> Vec<Notification> {
+    let principal = caller();
+    let limit = limit.unwrap_or(MAX_NOTIFICATION_PAGE).clamp(1, MAX_NOTIFICATION_PAGE) as usize;
+    let upper = cursor.unwrap_or(u64::MAX);
+    RECEIVER_INDEX.with(|i| {
+        NOTIFICATIONS.with(|notifications| {
+            let notifications = notifications.borrow();
+            i.borrow()
+                .range((principal, 0)..(principal, upper))
+                .rev()
+                .filter_map(|(_, id)| notifications.get(id))
+                .filter(|n| notification_type.map(|t| n.notification_type == t).unwrap_or(true))
+                .take(limit)
+                .cloned()
+                .collect()
+        })
+    })
+}
+
+fn own_notification(principal: Principal, notification_id: u64) -> Result<(), String> {
+    match NOTIFICATIONS.with(|n| n.borrow().get(&notification_id).map(|n| n.receiver)) {
+        Some(receiver) if receiver == principal => Ok(()),
+        Some(_) => Err("Unauthorized".to_string()),
+        None => Err("Notification not found".to_string()),
+    }
+}
+
+/// Log the notifications `user` just read, if any
+fn read_event(user: Principal, notification_ids: Vec<u64>) {
+    if !notification_ids.is_empty() {
+        events::record_for(user, EventKind::NotificationsRead { user, notification_ids });
+    }
+}
+
+#[ic_cdk::update]
+pub fn mark_notification_read(notification_id: u64) -> Result<String, String> {
+    let principal = auth::registered_caller()?;
+    own_notification(principal, notification_id)?;
+    if set_read(notification_id) {
+        read_event(principal, vec![notification_id]);
+    }
+    Ok("Notification marked as read".to_string())
+}
+
+/// Mark the given notifications read, skipping ids that aren't the caller's.
+/// Returns how many changed.
+#[ic_cdk::update]
+pub fn mark_notifications_read(notification_ids: Vec<u64>) -> Result<u64, String> {
+    let principal = auth::registered_caller()?;
+    let changed: Vec<u64> = notification_ids
+        .into_iter()
+        .filter(|id| own_notification(principal, *id).is_ok() && set_read(*id))
+        .collect();
+    let count = changed.len() as u64;
+    read_event(principal, changed);
+    Ok(count)
+}
+
+/// Mark everything up to notification `up_to` (or everything) read. Returns how many changed.
+#[ic_cdk::update]
+pub fn mark_all_notifications_read(up_to: Option<u64>) -> Result<u64, String> {
+    let principal = auth::registered_caller()?;
+    let changed: Vec<u64> = ids_of(principal, up_to.unwrap_or(u64::MAX)).into_iter().filter(|id| set_read(*id)).collect();
+    let count = changed.len() as u64;
+    read_event(principal, changed);
+    Ok(count)
+}
+
+#[ic_cdk::update]
+pub fn delete_notification(notification_id: u64) -> Result<String, String> {
+    let principal = auth::registered_caller()?;
+    own_notification(principal, notification_id)?;
+    remove_notification(notification_id);
+    let kind = EventKind::NotificationsDeleted { user: principal, notification_ids: vec![notification_id] };
+    events::record_for(principal, kind);
+    Ok("Notification deleted".to_string())
+}
+
+/// Delete all of the caller's notifications. Returns how many were removed.
+#[ic_cdk::update]
+pub fn clear_notifications() -> Result<u64, String> {
+    let principal = auth::registered_caller()?;
+    let ids = ids_of(principal, u64::MAX);
+    let count = ids.len() as u64;
+    for id in &ids {
+        remove_notification(*id);
+    }
+    if !ids.is_empty() {
+        events::record_for(principal, EventKind::NotificationsDeleted { user: principal, notification_ids: ids });
+    }
+    Ok(count)
+}
+
+#[ic_cdk::query]
+pub fn get_unread_notification_count() -> u64 {
+    let principal = caller();
+    UNREAD_COUNTS.with(|c| c.borrow().get(&principal).copied().unwrap_or(0))
+}
+
+#[ic_cdk::update]
+pub fn set_notification_preferences(preferences: NotificationPreferences) -> Result<NotificationPreferences, String> {
+    let principal = auth::registered_caller()?;
+    if let Some(q) = preferences.quiet_hours {
+        if q.start_minute as i64 >= MINUTES_PER_DAY || q.end_minute as i64 >= MINUTES_PER_DAY {
+            return Err("Quiet hours must be given in minutes after midnight (0-1439)".to_string());
+        }
+        if q.utc_offset_minutes.abs() > 14 * 60 {
+            return Err("UTC offset must be within ±14 hours".to_string());
+        }
+    }
+    if preferences.min_account_age_days.map(|d| d > MAX_MIN_ACCOUNT_AGE_DAYS).unwrap_or(false) {
+        return Err(format!("Minimum account age can be at most {} days", MAX_MIN_ACCOUNT_AGE_DAYS));
+    }
+    PREFERENCES.with(|p| p.borrow_mut().insert(principal, preferences));
+    events::record_for(principal, EventKind::SettingChanged { user: principal, setting: Setting::NotificationPreferences });
+    Ok(preferences)
+}
+
+#[ic_cdk::query]
+pub fn get_notification_preferences() -> NotificationPreferences {
+    preferences(caller())
+}
+
+/// Admin only. A lower cap applies to each receiver as new notifications arrive.
+#[ic_cdk::update]
+pub fn set_notification_retention(policy: RetentionPolicy) -> Result<RetentionPolicy, String> {
+    let admin = auth::admin_caller()?;
+    if policy.max_age_days == 0 || policy.max_per_receiver == 0 {
+        return Err("Retention limits must be at least 1".to_string());
+    }
+    RETENTION.with(|r| *r.borrow_mut() = policy);
+    let kind = EventKind::AdminSettingChanged { by: admin, setting: AdminSetting::NotificationRetention };
+    events::record_private(kind, Vec::new());
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+pub fn get_notification_retention() -> RetentionPolicy {
+    RETENTION.with(|r| *r.borrow())
+}
+
+/// The caller's notifications grouped by type and target, most recent group first
+#[ic_cdk::query]
+pub fn get_notification_groups(cursor: Option<NotificationGroupCursor>, limit: u32) -> NotificationGroupPage {
+    let me = caller();
+    let limit = limit.clamp(1, MAX_GROUP_PAGE) as usize;
+    let upper = match cursor {
+        Some(c) => (me, c.latest_at, c.group_id),
+        None => (me, u64::MAX, u64::MAX),
+    };
+
+    let keys: Vec<(u64, u64)> = GROUP_ORDER.with(|o| {
+        o.borrow()
+            .range((me, 0, 0)..upper)
+            .rev()
+            .take(limit + 1)
+            .map(|(_, at, id)| (*at, *id))
+            .collect()
+    });
+
+    let has_more = keys.len() > limit;
+    let groups: Vec<NotificationGroup> = GROUPS.with(|g| {
+        let g = g.borrow();
+        keys.iter()
+            .take(limit)
+            .filter_map(|(_, id)| g.get(id).and_then(|group| group_view(*id, group)))
+            .collect()
+    });
+    let next_cursor = if has_more {
+        keys.get(limit - 1).map(|(at, id)| NotificationGroupCursor { latest_at: *at, group_id: *id })
+    } else {
+        None
+    };
+    NotificationGroupPage { groups, next_cursor }
+}
+
+/// Mark every notification in a group as read
+#[ic_cdk::update]
+pub fn mark_notification_group_read(group_id: u64) -> Result<String, String> {
+    let me = auth::registered_caller()?;
+    let ids = GROUPS.with(|g| match g.borrow().get(&group_id) {
+        Some(group) if group.key.0 == me => Ok(group.notification_ids.clone()),
+        _ => Err("Notification group not found".to_string()),
+    })?;
+    let changed: Vec<u64> = ids.into_iter().filter(|id| set_read(*id)).collect();
+    read_event(me, changed);
+    Ok("Notifications marked as read".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nanoseconds since the epoch at `hour:minute` UTC on day zero
+    fn at(hour: u64, minute: u64) -> u64 {
+        (hour * 60 + minute) * NANOS_PER_MINUTE
+    }
+
+    fn quiet(start: (u16, u16), end: (u16, u16), utc_offset_minutes: i16) -> QuietHours {
+        QuietHours { start_minute: start.0 * 60 + start.1, end_minute: end.0 * 60 + end.1, utc_offset_minutes }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let q = quiet((9, 0), (17, 0), 0);
+        assert!(!q.contains(at(8, 59)));
+        assert!(q.contains(at(9, 0)));
+        assert!(q.contains(at(16, 59)));
+        assert!(!q.contains(at(17, 0)));
+    }
+
+    #[test]
+    fn window_wrapping_past_midnight() {
+        let q = quiet((22, 0), (7, 0), 0);
+        assert!(q.contains(at(22, 0)));
+        assert!(q.contains(at(23, 59)));
+        assert!(q.contains(at(0, 0)));
+        assert!(q.contains(at(6, 59)));
+        assert!(!q.contains(at(7, 0)));
+        assert!(!q.contains(at(12, 0)));
+        // the next day too
+        assert!(q.contains(at(24 + 3, 0)));
+    }
+
+    #[test]
+    fn applies_the_utc_offset() {
+        // 22:00-07:00 at UTC+2 is 20:00-05:00 UTC
+        let east = quiet((22, 0), (7, 0), 120);
+        assert!(east.contains(at(20, 0)));
+        assert!(!east.contains(at(5, 0)));
+        // and at UTC-5 it is 03:00-12:00 UTC; 01:00 UTC is 20:00 the previous local day
+        let west = quiet((22, 0), (7, 0), -300);
+        assert!(west.contains(at(3, 0)));
+        assert!(!west.contains(at(12, 0)));
+        assert!(!west.contains(at(1, 0)));
+    }
+
+    #[test]
+    fn empty_window_never_applies() {
+        let q = quiet((8, 0), (8, 0), 0);
+        assert!(!q.contains(at(8, 0)));
+        assert!(!q.contains(at(20, 0)));
+    }
+}
@@ -0,0 +1,106 @@
+// Text matching shared by every search endpoint.
+//
+// Text is split into Unicode words and lowercased. A query matches when each
+// of its words is a prefix of some word in the text, so "ali" finds "Alice"
+// but "ice" does not.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Graphemes kept on each side of the first match in a snippet
+pub const SNIPPET_RADIUS: usize = 40;
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.unicode_words().map(|w| w.to_lowercase()).collect()
+}
+
+fn word_matches(query: &[String], word: &str) -> bool {
+    let word = word.to_lowercase();
+    query.iter().any(|q| word.starts_with(q.as_str()))
+}
+
+/// Every query token is a prefix of some word of `text`
+pub fn matches(query: &[String], text: &str) -> bool {
+    if query.is_empty() {
+        return false;
+    }
+    let words = tokenize(text);
+    query.iter().all(|q| words.iter().any(|w| w.starts_with(q.as_str())))
+}
+
+/// `text` cut down to the area around the first matching word
+pub fn snippet(text: &str, query: &[String]) -> String {
+    let Some(start) = text
+        .unicode_word_indices()
+        .find(|(_, word)| word_matches(query, word))
+        .map(|(i, _)| i)
+    else {
+        return crate::validation::truncate_graphemes(text, 2 * SNIPPET_RADIUS);
+    };
+
+    let before: Vec<&str> = text[..start].graphemes(true).collect();
+    let head = before.len().saturating_sub(SNIPPET_RADIUS);
+    let prefix = if head > 0 { "…" } else { "" };
+    format!(
+        "{}{}{}",
+        prefix,
+        before[head..].concat(),
+        crate::validation::truncate_graphemes(&text[start..], SNIPPET_RADIUS)
+    )
+}
+
+/// Lowercased `#tags` in `text`, without the `#`, each once
+pub fn hashtags(text: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        let Some(rest) = word.strip_prefix('#') else { continue };
+        let tag: String = rest.chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect();
+        let tag = tag.to_lowercase();
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_lowercases_words_and_drops_punctuation() {
+        assert_eq!(tokenize("Hello, World! It's déjà-vu"), vec!["hello", "world", "it's", "déjà", "vu"]);
+        assert!(tokenize("  ...  ").is_empty());
+    }
+
+    #[test]
+    fn matches_needs_every_token_as_a_word_prefix() {
+        let query = tokenize("rust ic");
+        assert!(matches(&query, "Writing Rust for the Internet Computer (ICP)"));
+        assert!(!matches(&query, "Writing Rust"));
+        assert!(!matches(&[], "anything"));
+    }
+
+    #[test]
+    fn snippet_centres_on_the_first_match() {
+        let text = format!("{} needle {}", "a ".repeat(100), "b ".repeat(100));
+        let snippet = snippet(&text, &tokenize("need"));
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("needle"));
+        assert_eq!(snippet.chars().count(), 2 * SNIPPET_RADIUS + 2);
+    }
+
+    #[test]
+    fn snippet_without_a_match_is_the_start() {
+        assert_eq!(snippet("short text", &tokenize("missing")), "short text");
+        let long = "x".repeat(3 * SNIPPET_RADIUS);
+        assert_eq!(snippet(&long, &tokenize("missing")).chars().count(), 2 * SNIPPET_RADIUS + 1);
+    }
+
+    #[test]
+    fn hashtags_are_lowercased_and_unique() {
+        assert_eq!(hashtags("#Rust is fun, #rust #ICP_dev! #"), vec!["rust", "icp_dev"]);
+        assert_eq!(hashtags("no tags here, not even a#tag"), Vec::<String>::new());
+        assert_eq!(hashtags("#café."), vec!["café"]);
+    }
+}
@@ -0,0 +1,155 @@
+// Delta sync for polling clients.
+//
+// Every change to a post, notification, message or follow takes the next
+// canister-wide sequence number. Only the latest change per item is kept, so
+// `get_updates_since` walks forward from the client's cursor and returns each
+// changed item once: in its current state, or as a deletion if it is gone.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::messaging::{self, Message};
+use crate::notifications::{self, Notification};
+use crate::{Post, POSTS, USERS};
+
+/// Changes looked at per call
+pub const MAX_SYNC_SCAN: usize = 1_000;
+/// Items returned per call; posts and messages can be large
+pub const MAX_SYNC_ITEMS: usize = 100;
+
+/// What changed; the current state is looked up when syncing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Change {
+    Post { post_id: u64, author: Principal },
+    Notification { notification_id: u64, receiver: Principal },
+    Message { message_id: u64, conversation_id: u64 },
+    Follow { follower: Principal, followee: Principal },
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct FollowChange {
+    pub follower: Principal,
+    pub followee: Principal,
+    /// False once unfollowed or blocked
+    pub following: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SyncUpdates {
+    /// New or changed posts by the caller or people they follow
+    pub posts: Vec<Post>,
+    pub deleted_post_ids: Vec<u64>,
+    pub notifications: Vec<Notification>,
+    pub deleted_notification_ids: Vec<u64>,
+    pub messages: Vec<Message>,
+    /// Messages purged, unsent or deleted by the caller
+    pub deleted_message_ids: Vec<u64>,
+    /// Follows to or from the caller
+    pub follows: Vec<FollowChange>,
+    /// Pass back to get what changes next
+    pub cursor: u64,
+    /// More changes are waiting; call again straight away
+    pub has_more: bool,
+}
+
+// Storage
+thread_local! {
+    static CHANGES: RefCell<BTreeMap<u64, Change>> = const { RefCell::new(BTreeMap::new()) };
+    // sequence number of each item's latest change in CHANGES
+    static LATEST: RefCell<BTreeMap<Change, u64>> = const { RefCell::new(BTreeMap::new()) };
+    static SEQUENCE: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// Internal API
+
+/// Record that an item changed; its earlier entry is dropped
+pub(crate) fn touch(change: Change) {
+    let seq = SEQUENCE.with(|s| {
+        let mut s = s.borrow_mut();
+        *s += 1;
+        *s
+    });
+    let previous = LATEST.with(|l| l.borrow_mut().insert(change, seq));
+    CHANGES.with(|c| {
+        let mut c = c.borrow_mut();
+        if let Some(previous) = previous {
+            c.remove(&previous);
+        }
+        c.insert(seq, change);
+    });
+}
+
+// Helpers
+
+/// Add `change` to `updates` if the caller should hear about it; false if not
+fn collect(me: Principal, following: &[Principal], change: Change, updates: &mut SyncUpdates) -> bool {
+    match change {
+        Change::Post { post_id, author } => {
+            if author != me && !following.contains(&author) {
+                return false;
+            }
+            match POSTS.with(|p| p.borrow().get(&post_id).cloned()) {
+                Some(post) => updates.posts.push(post),
+                None => updates.deleted_post_ids.push(post_id),
+            }
+        }
+        Change::Notification { notification_id, receiver } => {
+            if receiver != me {
+                return false;
+            }
+            match notifications::notification(notification_id) {
+                Some(notification) => updates.notifications.push(notification),
+                None => updates.deleted_notification_ids.push(notification_id),
+            }
+        }
+        Change::Message { message_id, conversation_id } => {
+            if !messaging::is_participant(me, conversation_id) {
+                return false;
+            }
+            match messaging::visible_message(me, message_id) {
+                Some(message) => updates.messages.push(message),
+                None => updates.deleted_message_ids.push(message_id),
+            }
+        }
+        Change::Follow { follower, followee } => {
+            if follower != me && followee != me {
+                return false;
+            }
+            let following = USERS.with(|u| {
+                u.borrow().get(&follower).map(|user| user.following.contains(&followee)).unwrap_or(false)
+            });
+            updates.follows.push(FollowChange { follower, followee, following });
+        }
+    }
+    true
+}
+
+// Endpoints
+
+/// Everything relevant to the caller that changed after `cursor`; start from 0.
+/// Each item comes once, in its latest state, however often it changed.
+#[ic_cdk::query]
+pub fn get_updates_since(cursor: u64) -> SyncUpdates {
+    let me = caller();
+    let following = USERS.with(|u| u.borrow().get(&me).map(|user| user.following.clone()).unwrap_or_default());
+    let batch: Vec<(u64, Change)> = CHANGES.with(|c| {
+        c.borrow().range(cursor.saturating_add(1)..).take(MAX_SYNC_SCAN + 1).map(|(seq, ch)| (*seq, *ch)).collect()
+    });
+
+    let mut updates = SyncUpdates { cursor: SEQUENCE.with(|s| *s.borrow()).max(cursor), ..Default::default() };
+    let mut items = 0;
+    for (i, (seq, change)) in batch.iter().enumerate() {
+        if i == MAX_SYNC_SCAN || items == MAX_SYNC_ITEMS {
+            // resume after the last change looked at
+            updates.cursor = seq - 1;
+            updates.has_more = true;
+            break;
+        }
+        if collect(me, &following, *change, &mut updates) {
+            items += 1;
+        }
+    }
+    updates
+}
@@ -0,0 +1,124 @@
+// Precomputed home timelines.
+//
+// Each user has a bounded list of post ids from themselves and the people they
+// follow, oldest first. New posts and reposts are fanned out to the author's
+// followers as they are written; following someone back-fills their recent
+// posts, and unfollowing or blocking takes them out again. Reading the
+// Following feed is then a lookup instead of a scan over every post.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{is_blocked_either, Post, POSTS, USERS};
+
+/// Post ids kept per timeline; older ones drop off the front
+pub const TIMELINE_CAPACITY: usize = 500;
+/// Recent posts of a newly followed user copied into the follower's timeline
+pub const BACKFILL: usize = 50;
+
+// Storage
+thread_local! {
+    static TIMELINES: RefCell<BTreeMap<Principal, VecDeque<u64>>> = const { RefCell::new(BTreeMap::new()) };
+    // (author, post id): each author's posts and reposts in order
+    static AUTHOR_POSTS: RefCell<BTreeSet<(Principal, u64)>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Helpers
+
+fn followers_of(user: Principal) -> Vec<Principal> {
+    USERS.with(|u| u.borrow().get(&user).map(|p| p.followers.clone()).unwrap_or_default())
+}
+
+fn remove_from(user: Principal, post_ids: &BTreeSet<u64>) {
+    TIMELINES.with(|t| {
+        if let Some(timeline) = t.borrow_mut().get_mut(&user) {
+            timeline.retain(|id| !post_ids.contains(id));
+        }
+    });
+}
+
+// Internal API
+
+/// Fan a new post or repost out to its author and their followers.
+/// Post ids only grow, so it always goes at the back.
+pub(crate) fn on_post_created(author: Principal, post_id: u64) {
+    AUTHOR_POSTS.with(|a| a.borrow_mut().insert((author, post_id)));
+    TIMELINES.with(|t| {
+        let mut t = t.borrow_mut();
+        for user in std::iter::once(author).chain(followers_of(author)) {
+            let timeline = t.entry(user).or_default();
+            timeline.push_back(post_id);
+            if timeline.len() > TIMELINE_CAPACITY {
+                timeline.pop_front();
+            }
+        }
+    });
+}
+
+/// Take a deleted post out of its author's and their followers' timelines
+pub(crate) fn on_post_deleted(author: Principal, post_id: u64) {
+    AUTHOR_POSTS.with(|a| a.borrow_mut().remove(&(author, post_id)));
+    let ids = BTreeSet::from([post_id]);
+    for user in std::iter::once(author).chain(followers_of(author)) {
+        remove_from(user, &ids);
+    }
+}
+
+/// Merge the followee's recent posts into the follower's timeline
+pub(crate) fn on_follow(follower: Principal, followee: Principal) {
+    let recent: Vec<u64> = AUTHOR_POSTS.with(|a| {
+        a.borrow()
+            .range((followee, 0)..=(followee, u64::MAX))
+            .rev()
+            .take(BACKFILL)
+            .map(|(_, id)| *id)
+            .collect()
+    });
+    TIMELINES.with(|t| {
+        let mut t = t.borrow_mut();
+        let timeline = t.entry(follower).or_default();
+        let mut merged: BTreeSet<u64> = timeline.iter().copied().collect();
+        merged.extend(recent);
+        let skip = merged.len().saturating_sub(TIMELINE_CAPACITY);
+        *timeline = merged.into_iter().skip(skip).collect();
+    });
+}
+
+/// Drop the followee's posts from the follower's timeline, e.g. on unfollow or block
+pub(crate) fn on_unfollow(follower: Principal, followee: Principal) {
+    let theirs: BTreeSet<u64> = AUTHOR_POSTS.with(|a| {
+        a.borrow().range((followee, 0)..=(followee, u64::MAX)).map(|(_, id)| *id).collect()
+    });
+    remove_from(follower, &theirs);
+}
+
+/// Up to `limit` post ids from the user's timeline older than `before`
+/// (from the newest if `None`), newest first
+pub(crate) fn recent(user: Principal, before: Option<u64>, limit: usize) -> Vec<u64> {
+    TIMELINES.with(|t| {
+        let t = t.borrow();
+        let Some(timeline) = t.get(&user) else { return Vec::new() };
+        // ids are kept in ascending order
+        let end = before.map(|before| timeline.partition_point(|id| *id < before)).unwrap_or(timeline.len());
+        timeline.range(..end).rev().take(limit).copied().collect()
+    })
+}
+
+/// Up to `limit` posts for `ids` that still exist, in the same order. Reposts
+/// of someone blocked either way are left out: a block only takes the blocked
+/// user's own posts out of timelines, not other people's reposts of them.
+pub(crate) fn load(viewer: Principal, ids: &[u64], limit: usize) -> Vec<Post> {
+    POSTS.with(|p| {
+        let p = p.borrow();
+        ids.iter()
+            .filter_map(|id| p.get(id))
+            .filter(|post| {
+                let original_author = post.original_post_id.and_then(|id| p.get(&id)).map(|o| o.author);
+                original_author.is_none_or(|author| author == viewer || !is_blocked_either(viewer, author))
+            })
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
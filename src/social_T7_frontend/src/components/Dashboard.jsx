@@ -36,9 +36,12 @@ const Dashboard = ({ actor, user, principal, onLogout, onUserUpdate }) => {
 
   const loadNotifications = async () => {
     try {
-      const notifs = await actor.get_notifications();
+      const [notifs, unread] = await Promise.all([
+        actor.get_notifications([], [], []),
+        actor.get_unread_notification_count(),
+      ]);
       setNotifications(notifs);
-      setUnreadCount(notifs.filter((n) => !n.read).length);
+      setUnreadCount(Number(unread));
     } catch (error) {
       console.error("Error loading notifications:", error);
     }
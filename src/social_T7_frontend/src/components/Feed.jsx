@@ -2,10 +2,15 @@ import React, { useState, useEffect, useRef } from 'react';
 import PostCard from './PostCard';
 import { RefreshCw } from 'lucide-react';
 
+// Posts per get_feed call, as returned by get_feed_weights
+const FEED_PAGE = 50;
+
 const Feed = ({ actor, user, onUserProfileView }) => {
   const [posts, setPosts] = useState([]);
   const [loading, setLoading] = useState(true);
   const [refreshing, setRefreshing] = useState(false);
+  const [loadingMore, setLoadingMore] = useState(false);
+  const [hasMore, setHasMore] = useState(false);
 
   // If your scroll container is window, this ref isn't needed.
   const scrollContainerRef = useRef(null);
@@ -19,8 +24,9 @@ const Feed = ({ actor, user, onUserProfileView }) => {
     const y = window.scrollY;
     try {
       setLoading(true);
-      const feedPosts = await actor.get_feed();
-      setPosts(feedPosts);
+      const feedItems = await actor.get_feed([{ Following: null }], []);
+      setPosts(feedItems.map((item) => item.post));
+      setHasMore(feedItems.length === FEED_PAGE);
     } catch (error) {
       console.error('Error loading feed:', error);
     } finally {
@@ -29,6 +35,21 @@ const Feed = ({ actor, user, onUserProfileView }) => {
     }
   };
 
+  const loadMore = async () => {
+    if (posts.length === 0) return;
+    try {
+      setLoadingMore(true);
+      const before = posts[posts.length - 1].post_id;
+      const feedItems = await actor.get_feed([{ Following: null }], [before]);
+      setPosts((current) => [...current, ...feedItems.map((item) => item.post)]);
+      setHasMore(feedItems.length === FEED_PAGE);
+    } catch (error) {
+      console.error('Error loading more posts:', error);
+    } finally {
+      setLoadingMore(false);
+    }
+  };
+
   const handleRefresh = async () => {
     setRefreshing(true);
     await loadFeed();
@@ -101,6 +122,18 @@ const Feed = ({ actor, user, onUserProfileView }) => {
           </div>
         )}
       </div>
+
+      {hasMore && (
+        <div className="mt-6 text-center">
+          <button
+            onClick={loadMore}
+            disabled={loadingMore}
+            className="px-4 py-2 bg-white text-blue-600 rounded-xl shadow-sm hover:bg-gray-50 transition-colors disabled:opacity-50"
+          >
+            {loadingMore ? 'Loading...' : 'Load more'}
+          </button>
+        </div>
+      )}
     </div>
   );
 };
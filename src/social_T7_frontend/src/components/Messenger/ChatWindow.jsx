@@ -24,7 +24,7 @@ export default function ChatWindow({ actor, user, peer, onBack, isMobileChat })
       setMessages(arr);
 
       // mark last incoming as seen
-      const incoming = arr.filter((m) => m?.to?.toString?.() === meStr);
+      const incoming = arr.filter((m) => m?.from?.toString?.() !== meStr);
       if (incoming.length > 0) {
         const last = incoming[incoming.length - 1];
         await actor.mark_seen(peer, toBig(last?.id));
@@ -65,7 +65,7 @@ export default function ChatWindow({ actor, user, peer, onBack, isMobileChat })
     e.preventDefault();
     if (!text.trim() || !peer) return;
     try {
-      const res = await actor.send_message(peer, text.trim());
+      const res = await actor.send_message(peer, text.trim(), []);
       if (res?.Err) {
         alert(res.Err);
         return;
@@ -24,9 +24,23 @@ export default function MessageBubble({ msg, meStr }) {
     }
   }, [msg?.created_at]);
 
+  // status is a candid variant: { Sent: null } | { Delivered: null } | { Read: null }
+  const status = msg?.status ? Object.keys(msg.status)[0] : "Sent";
+  const read = status === "Read";
+
   const timeStr = timeFmt.format(new Date(ms));
   const fullStr = fullFmt.format(new Date(ms));
 
+  if (msg?.system) {
+    return (
+      <div className="flex justify-center" title={fullStr}>
+        <div className="text-xs text-gray-500 italic">
+          {mine ? "You" : "They"} {msg?.content ?? ""}
+        </div>
+      </div>
+    );
+  }
+
   return (
     <div className={`flex ${mine ? "justify-end" : "justify-start"}`}>
       <div
@@ -36,7 +50,20 @@ export default function MessageBubble({ msg, meStr }) {
         }`}
         title={fullStr}
       >
-        <div>{msg?.content ?? ""}</div>
+        {msg?.unsent ? (
+          <div className="italic opacity-70">This message was unsent</div>
+        ) : msg?.encrypted?.length > 0 ? (
+          <div className="italic opacity-70">Encrypted message</div>
+        ) : (
+          <>
+            {msg?.attachments?.map((a) => (
+              <div key={String(a.attachment_id)} className="text-xs opacity-80">
+                📎 {a.file_name || "attachment"}
+              </div>
+            ))}
+            {msg?.content ? <div>{msg.content}</div> : null}
+          </>
+        )}
 
         <div
           className={`mt-1 flex items-center gap-2 text-[10px] ${
@@ -44,14 +71,15 @@ export default function MessageBubble({ msg, meStr }) {
           }`}
         >
           <span>{timeStr}</span>
+          {msg?.edited_at?.length > 0 && !msg?.unsent && <span>edited</span>}
 
           {mine && (
             <span
               className={`inline-flex items-center gap-0.5 ${
-                msg?.seen ? "text-blue-300" : ""
+                read ? "text-blue-300" : ""
               }`}
-              aria-label={msg?.seen ? "Seen" : "Sent"}
-              title={msg?.seen ? "Seen" : "Sent"}
+              aria-label={read ? "Seen" : status}
+              title={read ? "Seen" : status}
             >
               {/* double-tick icon */}
               <svg width="14" height="14" viewBox="0 0 24 24" fill="currentColor" aria-hidden="true">
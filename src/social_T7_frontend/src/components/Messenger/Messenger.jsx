@@ -11,8 +11,10 @@ export default function Messenger({ actor, user }) {
 
   const loadInbox = async () => {
     try {
-      const list = await actor.get_inbox();
-      setPeers(Array.isArray(list) ? list : []);
+      const page = await actor.get_inbox([], 50);
+      // direct conversations only; `peer` is an opt profile snippet
+      const items = Array.isArray(page?.items) ? page.items : [];
+      setPeers(items.flatMap((item) => item.peer.map((p) => p.user_principal)));
     } catch (e) {
       console.error("get_inbox failed", e);
     }